@@ -11,26 +11,156 @@ use reqwest::{
 
 use firmware::Metadata;
 use runtime_settings::RuntimeSettings;
+use settings::Auth;
 use update_package::UpdatePackage;
 use Result;
 
+pub(crate) mod omaha;
+
 #[cfg(test)]
 pub(crate) mod tests;
 
+/// The three operations the state machine needs from an update backend.
+/// `Api` speaks the updatehub protocol; `omaha::OmahaService` speaks
+/// Google's Omaha protocol instead, so the same state machine can be
+/// pointed at either kind of server by selecting the implementation
+/// from `settings.network.update_protocol`.
+pub(crate) trait UpdateService {
+    fn probe(&self, runtime_settings: &RuntimeSettings, firmware: &Metadata) -> Result<ProbeResponse>;
+
+    fn download_object(
+        &self,
+        product_uid: &str,
+        package_uid: &str,
+        download_dir: &Path,
+        object: &str,
+    ) -> Result<()>;
+
+    fn report(
+        &self,
+        state: &str,
+        firmware: &Metadata,
+        package_uid: &str,
+        previous_state: Option<&str>,
+        error_message: Option<String>,
+    ) -> Result<()>;
+}
+
 pub(crate) struct Api<'a> {
     server: &'a str,
+    auth: Option<&'a Auth>,
 }
 
 #[derive(Debug)]
 pub(crate) enum ProbeResponse {
-    NoUpdate,
-    Update(UpdatePackage),
+    /// The second field is the server-dictated `try-again-in` header,
+    /// if present: how many seconds the next poll should wait before
+    /// probing again, overriding `settings.polling.interval` (see
+    /// `RuntimeSettings::set_server_requested_interval`).
+    NoUpdate(Option<i64>),
+    Update(UpdatePackage, Option<RolloutWindow>, Option<i64>),
     ExtraPoll(i64),
 }
 
+/// A staged rollout gate sent alongside an available update, so a whole
+/// fleet does not download it at the same instant. `fraction` is the
+/// rollout's current target (`rollout-fraction`); when `window_seconds`
+/// (`rollout-window-seconds`) is also set, that target is itself
+/// ramped linearly from 0 up to `fraction` over the window, measured
+/// from the update's first-seen timestamp.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct RolloutWindow {
+    pub fraction: f32,
+    pub window_seconds: Option<i64>,
+}
+
+/// A cached OAuth2 access token, reused until it expires.
+struct CachedToken {
+    access_token: String,
+    expires_at: std::time::Instant,
+}
+
+lazy_static::lazy_static! {
+    static ref TOKEN_CACHE: std::sync::Mutex<Option<CachedToken>> = std::sync::Mutex::new(None);
+}
+
 impl<'a> Api<'a> {
     pub(crate) fn new(server: &'a str) -> Api<'a> {
-        Api { server }
+        Api { server, auth: None }
+    }
+
+    /// Builds a client that authenticates every request `Api` makes
+    /// (`probe`, `download_object`/`download_object_with_progress` and
+    /// `report`/`report_with_progress` all go through `client()` below),
+    /// either with a static bearer token or by performing an OAuth2
+    /// client-credentials grant against `auth.token_url` and caching
+    /// the resulting access token until it expires.
+    pub(crate) fn with_auth(server: &'a str, auth: &'a Auth) -> Api<'a> {
+        Api { server, auth: Some(auth) }
+    }
+
+    /// Returns the bearer token to use for this request, performing
+    /// (and caching) an OAuth2 client-credentials grant if needed, or
+    /// transparently fetching a fresh one if the cached token expired.
+    fn access_token(&self) -> Result<Option<String>> {
+        let auth = match self.auth {
+            Some(auth) => auth,
+            None => return Ok(None),
+        };
+
+        if let Some(token) = &auth.token {
+            return Ok(Some(token.clone()));
+        }
+
+        let (client_id, client_secret, token_url) =
+            match (&auth.client_id, &auth.client_secret, &auth.token_url) {
+                (Some(id), Some(secret), Some(url)) => (id, secret, url),
+                _ => return Ok(None),
+            };
+
+        {
+            let cache = TOKEN_CACHE.lock().unwrap();
+            if let Some(cached) = cache.as_ref() {
+                if cached.expires_at > std::time::Instant::now() {
+                    return Ok(Some(cached.access_token.clone()));
+                }
+            }
+        }
+
+        #[derive(Deserialize)]
+        struct TokenResponse {
+            access_token: String,
+            #[serde(default = "default_expires_in")]
+            expires_in: u64,
+        }
+        fn default_expires_in() -> u64 {
+            3600
+        }
+
+        let response: TokenResponse = Client::new()
+            .post(token_url.as_str())
+            .form(&[
+                ("grant_type", "client_credentials"),
+                ("client_id", client_id.as_str()),
+                ("client_secret", client_secret.as_str()),
+            ])
+            .send()?
+            .json()?;
+
+        let access_token = response.access_token;
+        *TOKEN_CACHE.lock().unwrap() = Some(CachedToken {
+            access_token: access_token.clone(),
+            expires_at: std::time::Instant::now() + Duration::from_secs(response.expires_in),
+        });
+
+        Ok(Some(access_token))
+    }
+
+    /// Drops the cached access token, so the next request performs a
+    /// fresh client-credentials grant instead of reusing one the server
+    /// has stopped accepting.
+    fn invalidate_token(&self) {
+        *TOKEN_CACHE.lock().unwrap() = None;
     }
 
     fn client(&self) -> Result<Client> {
@@ -42,6 +172,12 @@ impl<'a> Api<'a> {
             HeaderName::from_static("api-content-type"),
             "application/vnd.updatehub-v1+json".parse()?,
         );
+        if let Some(access_token) = self.access_token()? {
+            headers.insert(
+                reqwest::header::AUTHORIZATION,
+                format!("Bearer {}", access_token).parse()?,
+            );
+        }
 
         Ok(Client::builder()
             .timeout(Duration::from_secs(10))
@@ -49,23 +185,51 @@ impl<'a> Api<'a> {
             .build()?)
     }
 
+    /// Builds a request from `build_request` and sends it, transparently
+    /// invalidating the cached token and retrying once with a freshly
+    /// minted one if the server rejects the first attempt as
+    /// unauthorized — the cached token may have simply expired since it
+    /// was minted, and most callers would rather pay for a second
+    /// request than abort an entire probe/report over it.
+    fn send_with_reauth<F>(&self, mut build_request: F) -> Result<reqwest::Response>
+    where
+        F: FnMut(&Client) -> reqwest::RequestBuilder,
+    {
+        let mut response = build_request(&self.client()?).send()?;
+        if response.status() == StatusCode::UNAUTHORIZED {
+            self.invalidate_token();
+            response = build_request(&self.client()?).send()?;
+        }
+        Ok(response)
+    }
+
     pub fn probe(
         &self,
         runtime_settings: &RuntimeSettings,
         firmware: &Metadata,
     ) -> Result<ProbeResponse> {
-        let mut response = self
-            .client()?
-            .post(&format!("{}/upgrades", &self.server))
-            .header(
-                HeaderName::from_static("api-retries"),
-                runtime_settings.retries(),
-            )
-            .json(firmware)
-            .send()?;
+        let mut response = self.send_with_reauth(|client| {
+            client
+                .post(&format!("{}/upgrades", &self.server))
+                .header(
+                    HeaderName::from_static("api-retries"),
+                    runtime_settings.retries(),
+                )
+                .json(firmware)
+        })?;
+
+        let try_again_in = response
+            .headers()
+            .get("try-again-in")
+            .and_then(|try_again_in| try_again_in.to_str().ok())
+            .and_then(|try_again_in| try_again_in.parse().ok());
 
         match response.status() {
-            StatusCode::NOT_FOUND => Ok(ProbeResponse::NoUpdate),
+            StatusCode::NOT_FOUND => Ok(ProbeResponse::NoUpdate(try_again_in)),
+            StatusCode::UNAUTHORIZED => {
+                self.invalidate_token();
+                bail!("Unauthorized. Status: {}", response.status());
+            }
             StatusCode::OK => {
                 if let Some(extra_poll) = response
                     .headers()
@@ -76,14 +240,60 @@ impl<'a> Api<'a> {
                     return Ok(ProbeResponse::ExtraPoll(extra_poll));
                 }
 
-                Ok(ProbeResponse::Update(UpdatePackage::parse(
-                    &response.text()?,
-                )?))
+                let rollout = response
+                    .headers()
+                    .get("rollout-fraction")
+                    .and_then(|fraction| fraction.to_str().ok())
+                    .and_then(|fraction| fraction.parse().ok())
+                    .map(|fraction| RolloutWindow {
+                        fraction,
+                        window_seconds: response
+                            .headers()
+                            .get("rollout-window-seconds")
+                            .and_then(|window| window.to_str().ok())
+                            .and_then(|window| window.parse().ok()),
+                    });
+
+                Ok(ProbeResponse::Update(
+                    UpdatePackage::parse(&response.text()?)?,
+                    rollout,
+                    try_again_in,
+                ))
             }
             _ => bail!("Invalid response. Status: {}", response.status()),
         }
     }
 
+    /// Fetches the compact patch for `delta` instead of the full target
+    /// object, and reconstructs the target against the locally present
+    /// source object. Returns `Ok(false)` (without touching the
+    /// filesystem) if the patch couldn't be fetched, the source object
+    /// is missing, or the reconstructed object fails to verify, so the
+    /// caller can fall back to `download_object` for a full download.
+    pub fn download_delta_object(
+        &self,
+        product_uid: &str,
+        package_uid: &str,
+        download_dir: &Path,
+        delta: &crate::update_package::delta::DeltaObject,
+    ) -> Result<bool> {
+        use std::io::Read;
+
+        let mut response = self.client()?.get(&format!(
+            "{}/products/{}/packages/{}/objects/{}/delta",
+            &self.server, product_uid, package_uid, delta.sha256sum
+        )).send()?;
+
+        if !response.status().is_success() {
+            return Ok(false);
+        }
+
+        let mut patch = Vec::new();
+        response.read_to_end(&mut patch)?;
+
+        Ok(delta.reconstruct(download_dir, &patch))
+    }
+
     pub fn download_object(
         &self,
         product_uid: &str,
@@ -91,7 +301,51 @@ impl<'a> Api<'a> {
         download_dir: &Path,
         object: &str,
     ) -> Result<()> {
-        use std::fs::{create_dir_all, OpenOptions};
+        // Only the `Download` state threads its own `RuntimeSettings`
+        // through `download_object_with_progress` so a resume survives
+        // a restart; this plain wrapper has no caller-supplied one to
+        // checkpoint into, so it resumes only within this single call.
+        let runtime_settings = std::sync::Mutex::new(RuntimeSettings::default());
+        self.download_object_with_progress(
+            product_uid,
+            package_uid,
+            download_dir,
+            object,
+            &runtime_settings,
+            |_, _| {},
+        )
+    }
+
+    /// Same as `download_object`, but invokes `on_progress(downloaded_bytes,
+    /// total_bytes)` as chunks of the response body are written to disk, so
+    /// callers can surface byte-level download progress instead of a binary
+    /// downloading/downloaded flip.
+    ///
+    /// Also persists `(bytes_downloaded, sha256sum-of-bytes-on-disk)` for
+    /// `object` into `runtime_settings` as the download progresses, so a
+    /// crash or a dropped connection can resume from the saved offset
+    /// instead of starting over: on entry, a pre-existing file is only
+    /// trusted (and its bytes `Range`-requested onward) if it still
+    /// matches its last recorded checkpoint; otherwise it's discarded and
+    /// the object is fetched from scratch. The checkpoint is cleared once
+    /// the object finishes downloading.
+    pub fn download_object_with_progress<F>(
+        &self,
+        product_uid: &str,
+        package_uid: &str,
+        download_dir: &Path,
+        object: &str,
+        runtime_settings: &std::sync::Mutex<RuntimeSettings>,
+        mut on_progress: F,
+    ) -> Result<()>
+    where
+        F: FnMut(u64, Option<u64>),
+    {
+        use crypto_hash::{hex_digest, Algorithm};
+        use std::{
+            fs::{self, create_dir_all, OpenOptions},
+            io::{Read, Write},
+        };
 
         // FIXME: Discuss the need of packages inside the route
         let mut client = self.client()?.get(&format!(
@@ -106,27 +360,110 @@ impl<'a> Api<'a> {
         }
 
         let file = path.join(object);
+        let mut downloaded = 0;
         if file.exists() {
-            client = client.header(RANGE, format!("bytes={}-", file.metadata()?.len() - 1));
+            let on_disk = fs::read(&file)?;
+            let resumable = runtime_settings.lock().unwrap().download_progress(object).map_or(
+                false,
+                |(bytes, checksum)| {
+                    bytes == on_disk.len() as u64 && checksum == hex_digest(Algorithm::SHA256, &on_disk)
+                },
+            );
+
+            if resumable {
+                downloaded = on_disk.len() as u64;
+                client = client.header(RANGE, format!("bytes={}-", downloaded));
+            } else {
+                // Either there's no recorded checkpoint for this object,
+                // or the bytes on disk no longer match it (e.g. a
+                // previous run crashed mid-write): don't trust a partial
+                // file we can't verify, fetch the object from scratch.
+                fs::remove_file(&file)?;
+                runtime_settings.lock().unwrap().clear_download_progress(object)?;
+            }
         }
 
         let mut file = OpenOptions::new().create(true).append(true).open(&file)?;
         let mut response = client.send()?;
-        if response.status().is_success() {
-            response.copy_to(&mut file)?;
-            return Ok(());
+        if response.status() == StatusCode::UNAUTHORIZED {
+            self.invalidate_token();
+        }
+        if !response.status().is_success() {
+            bail!("Couldn't download the object {}", object);
         }
 
-        bail!("Couldn't download the object {}", object)
+        let total = response.content_length().map(|len| len + downloaded);
+
+        let mut buf = [0u8; 64 * 1024];
+        let result = loop {
+            match response.read(&mut buf) {
+                Ok(0) => break Ok(()),
+                Ok(read) => {
+                    if let Err(e) = file.write_all(&buf[..read]) {
+                        break Err(e.into());
+                    }
+                    downloaded += read as u64;
+                    on_progress(downloaded, total);
+                }
+                Err(e) => break Err(e.into()),
+            }
+        };
+
+        let on_disk = fs::read(download_dir.join(object))?;
+        let mut runtime_settings = runtime_settings.lock().unwrap();
+
+        // Verify the assembled file against the object id (its
+        // sha256sum) before trusting it: a corrupted transfer that
+        // still ended with a clean EOF would otherwise go uncaught.
+        // Don't leave a bad file or checkpoint behind — the next
+        // attempt should restart from scratch, not "resume" onto it.
+        let result = result.and_then(|()| {
+            if hex_digest(Algorithm::SHA256, &on_disk) != object {
+                fs::remove_file(download_dir.join(object))?;
+                runtime_settings.clear_download_progress(object)?;
+                bail!("downloaded object {} failed its sha256sum check", object);
+            }
+            Ok(())
+        });
+
+        match &result {
+            Ok(()) => runtime_settings.clear_download_progress(object)?,
+            Err(_) => {
+                if let Ok(on_disk) = fs::read(download_dir.join(object)) {
+                    runtime_settings.set_download_progress(
+                        object,
+                        on_disk.len() as u64,
+                        &hex_digest(Algorithm::SHA256, &on_disk),
+                    )?;
+                }
+            }
+        }
+
+        result
     }
 
     pub fn report(
         &self,
         state: &str,
-        firmware: &'a Metadata,
+        firmware: &Metadata,
         package_uid: &str,
         previous_state: Option<&str>,
         error_message: Option<String>,
+    ) -> Result<()> {
+        self.report_with_progress(state, firmware, package_uid, previous_state, error_message, None)
+    }
+
+    /// Same as `report`, but additionally attaches download progress so the
+    /// server (and any `/state` API consumer) can show a real percentage
+    /// rather than a binary downloading/downloaded flip.
+    pub fn report_with_progress(
+        &self,
+        state: &str,
+        firmware: &Metadata,
+        package_uid: &str,
+        previous_state: Option<&str>,
+        error_message: Option<String>,
+        progress: Option<DownloadProgress>,
     ) -> Result<()> {
         #[derive(Serialize)]
         #[serde(rename_all = "kebab-case")]
@@ -139,6 +476,8 @@ impl<'a> Api<'a> {
             previous_state: Option<&'a str>,
             #[serde(skip_serializing_if = "Option::is_none")]
             error_message: Option<String>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            progress: Option<DownloadProgress>,
         }
 
         let payload = Payload {
@@ -147,12 +486,120 @@ impl<'a> Api<'a> {
             package_uid,
             previous_state,
             error_message,
+            progress,
         };
 
+        self.send_with_reauth(|client| client.post(&format!("{}/report", &self.server)).json(&payload))?;
+        Ok(())
+    }
+
+    /// Same as `report`, but attaches per-object install outcomes (e.g.
+    /// objects skipped by `install-if-different`) instead of only the
+    /// coarse state transition.
+    pub fn report_operations(
+        &self,
+        state: &str,
+        firmware: &Metadata,
+        package_uid: &str,
+        operations: &[OperationResult],
+    ) -> Result<()> {
+        #[derive(Serialize)]
+        #[serde(rename_all = "kebab-case")]
+        struct Payload<'a> {
+            status: &'a str,
+            #[serde(flatten)]
+            firmware: &'a Metadata,
+            package_uid: &'a str,
+            operations: &'a [OperationResult],
+        }
+
         self.client()?
             .post(&format!("{}/report", &self.server))
-            .json(&payload)
+            .json(&Payload { status: state, firmware, package_uid, operations })
             .send()?;
         Ok(())
     }
 }
+
+impl<'a> UpdateService for Api<'a> {
+    fn probe(&self, runtime_settings: &RuntimeSettings, firmware: &Metadata) -> Result<ProbeResponse> {
+        self.probe(runtime_settings, firmware)
+    }
+
+    fn download_object(
+        &self,
+        product_uid: &str,
+        package_uid: &str,
+        download_dir: &Path,
+        object: &str,
+    ) -> Result<()> {
+        self.download_object(product_uid, package_uid, download_dir, object)
+    }
+
+    fn report(
+        &self,
+        state: &str,
+        firmware: &Metadata,
+        package_uid: &str,
+        previous_state: Option<&str>,
+        error_message: Option<String>,
+    ) -> Result<()> {
+        self.report(state, firmware, package_uid, previous_state, error_message)
+    }
+}
+
+/// Outcome of attempting to install a single object, reported
+/// alongside the coarse state enter/leave/error so the server can tell
+/// which object in a multi-object package did what.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ResultCode {
+    Installed,
+    /// The target already held the object's content, per
+    /// `update_package::install_if_different`, so nothing was written.
+    Skipped,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct OperationResult {
+    pub(crate) object: String,
+    pub(crate) result: ResultCode,
+}
+
+impl OperationResult {
+    pub(crate) fn skipped(object: impl Into<String>) -> Self {
+        Self { object: object.into(), result: ResultCode::Skipped }
+    }
+}
+
+/// Byte-level progress of an in-flight download, reported alongside the
+/// coarse state entry/exit so consumers can show a real percentage.
+///
+/// `download_size` is `None` until every in-flight object's size is
+/// known (e.g. right after resuming a partial download, before the
+/// `Content-Length`/`Content-Range` response header has been read), in
+/// which case `fraction_completed` is also `None` rather than lying
+/// about the actual progress.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct DownloadProgress {
+    pub bytes_downloaded: u64,
+    pub download_size: Option<u64>,
+    pub fraction_completed: Option<f32>,
+}
+
+impl DownloadProgress {
+    pub fn new(bytes_downloaded: u64, download_size: Option<u64>) -> Self {
+        let fraction_completed = download_size.map(|size| {
+            if size == 0 {
+                1.0
+            } else {
+                bytes_downloaded as f32 / size as f32
+            }
+        });
+
+        Self { bytes_downloaded, download_size, fraction_completed }
+    }
+}
@@ -0,0 +1,173 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A second `UpdateService` backend speaking Google's Omaha update
+//! protocol, as exercised by Fuchsia's isolated-ota tests, so the same
+//! state machine can target either an updatehub server or an Omaha
+//! service.
+
+use super::{ProbeResponse, UpdateService};
+use firmware::Metadata;
+use reqwest::Client;
+use runtime_settings::RuntimeSettings;
+use std::{path::Path, time::Duration};
+use update_package::UpdatePackage;
+use Result;
+
+pub(crate) struct OmahaService<'a> {
+    server: &'a str,
+}
+
+impl<'a> OmahaService<'a> {
+    pub(crate) fn new(server: &'a str) -> Self {
+        Self { server }
+    }
+
+    fn client(&self) -> Result<Client> {
+        Ok(Client::builder().timeout(Duration::from_secs(10)).build()?)
+    }
+}
+
+#[derive(Serialize)]
+struct Request<'a> {
+    version: &'a str,
+    #[serde(rename = "updatecheck")]
+    update_check: UpdateCheckRequest,
+}
+
+#[derive(Serialize)]
+struct UpdateCheckRequest {}
+
+#[derive(Deserialize)]
+struct Response {
+    #[serde(rename = "updatecheck")]
+    update_check: UpdateCheckResponse,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum UpdateCheckResponse {
+    #[serde(rename = "ok")]
+    Ok {
+        urls: Vec<Url>,
+        manifest: Manifest,
+    },
+    #[serde(rename = "noupdate")]
+    NoUpdate,
+}
+
+#[derive(Deserialize)]
+struct Url {
+    codebase: String,
+}
+
+#[derive(Deserialize)]
+struct Manifest {
+    packages: Vec<Package>,
+}
+
+#[derive(Deserialize)]
+struct Package {
+    name: String,
+}
+
+impl<'a> UpdateService for OmahaService<'a> {
+    fn probe(&self, _runtime_settings: &RuntimeSettings, firmware: &Metadata) -> Result<ProbeResponse> {
+        let request = Request {
+            version: &firmware.version,
+            update_check: UpdateCheckRequest {},
+        };
+
+        let response: Response = self
+            .client()?
+            .post(&format!("{}/service/update2/json", &self.server))
+            .json(&request)
+            .send()?
+            .json()?;
+
+        match response.update_check {
+            // Omaha has no equivalent of the updatehub protocol's
+            // `try-again-in` header, so there's nothing to pass along.
+            UpdateCheckResponse::NoUpdate => Ok(ProbeResponse::NoUpdate(None)),
+            UpdateCheckResponse::Ok { urls, manifest } => {
+                let package_url = match urls.first() {
+                    Some(url) => url.codebase.clone(),
+                    None => bail!("Omaha response had no update url"),
+                };
+                let package_uid = match manifest.packages.first() {
+                    Some(package) => package.name.clone(),
+                    None => bail!("Omaha response had no update package"),
+                };
+
+                Ok(ProbeResponse::Update(
+                    UpdatePackage::from_omaha_manifest(&package_url, &package_uid)?,
+                    None,
+                    None,
+                ))
+            }
+        }
+    }
+
+    fn download_object(
+        &self,
+        _product_uid: &str,
+        package_uid: &str,
+        download_dir: &Path,
+        object: &str,
+    ) -> Result<()> {
+        use std::{fs::create_dir_all, io::copy};
+
+        if !download_dir.exists() {
+            create_dir_all(download_dir)?;
+        }
+
+        let mut response = self
+            .client()?
+            .get(&format!("{}/packages/{}/{}", &self.server, package_uid, object))
+            .send()?;
+        if !response.status().is_success() {
+            bail!("Couldn't download the object {}", object);
+        }
+
+        let mut file = std::fs::File::create(download_dir.join(object))?;
+        copy(&mut response, &mut file)?;
+
+        Ok(())
+    }
+
+    fn report(
+        &self,
+        state: &str,
+        _firmware: &Metadata,
+        _package_uid: &str,
+        _previous_state: Option<&str>,
+        _error_message: Option<String>,
+    ) -> Result<()> {
+        // Omaha tracks progress through "event pings" rather than a
+        // free-form status report; a successful/failed install is
+        // reported as a ping with the corresponding event type.
+        #[derive(Serialize)]
+        struct EventPing<'a> {
+            #[serde(rename = "eventtype")]
+            event_type: &'a str,
+        }
+
+        let event_type = match state {
+            "error" => "3",
+            "downloading" => "13",
+            _ => "1",
+        };
+
+        let response = self
+            .client()?
+            .post(&format!("{}/service/update2/json", &self.server))
+            .json(&EventPing { event_type })
+            .send()?;
+        if !response.status().is_success() {
+            bail!("Event ping rejected. Status: {}", response.status());
+        }
+
+        Ok(())
+    }
+}
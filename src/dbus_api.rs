@@ -0,0 +1,166 @@
+// Copyright (C) 2019 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Exposes the same control surface as [`http_api`](crate::http_api) —
+//! probe, local/remote install, current state, download abort and the
+//! recent log buffer — as methods and properties on the
+//! `io.updatehub.Agent1` D-Bus system bus name, so desktop/embedded
+//! integrators can drive updatehub without speaking HTTP.
+//!
+//! This gateway is optional and is only spawned when
+//! `settings.network.dbus_gateway` is enabled; it reuses the exact
+//! request/response structs the HTTP gateway marshals to/from JSON.
+
+use crate::states::actor;
+use actix::Addr;
+use dbus::{
+    tree::{Factory, MTFn},
+    BusType, Connection, NameFlag,
+};
+use futures::future::Future;
+use std::sync::Arc;
+
+const BUS_NAME: &str = "io.updatehub.Agent1";
+const OBJECT_PATH: &str = "/io/updatehub/Agent1";
+const INTERFACE: &str = "io.updatehub.Agent1";
+
+/// Runs the D-Bus gateway until the process exits. Meant to be spawned
+/// on its own thread next to the HTTP server in `run()`.
+pub fn run(addr: Addr<actor::Machine>) -> Result<(), dbus::Error> {
+    let conn = Connection::get_private(BusType::System)?;
+    conn.register_name(BUS_NAME, NameFlag::ReplaceExisting as u32)?;
+
+    let addr = Arc::new(addr);
+    let factory = Factory::new_fn::<MTFn>();
+
+    let tree = factory.tree(()).add(
+        factory.object_path(OBJECT_PATH, ()).introspectable().add(
+            factory
+                .interface(INTERFACE, ())
+                .add_m(probe_method(&factory, addr.clone()))
+                .add_m(local_install_method(&factory, addr.clone()))
+                .add_m(remote_install_method(&factory, addr.clone()))
+                .add_m(state_method(&factory, addr.clone()))
+                .add_m(abort_download_method(&factory, addr.clone()))
+                .add_m(log_method(&factory, addr)),
+        ),
+    );
+
+    tree.set_registered(&conn, true)?;
+    conn.add_handler(tree);
+
+    loop {
+        conn.incoming(1000).next();
+    }
+}
+
+fn probe_method(
+    factory: &Factory<MTFn>,
+    addr: Arc<Addr<actor::Machine>>,
+) -> dbus::tree::Method<MTFn, ()> {
+    factory
+        .method("Probe", (), move |m| {
+            let server_address = m.msg.read1::<&str>().ok().map(String::from);
+            let response = addr.send(actor::probe::Request(server_address)).wait();
+            let (busy, state) = match response {
+                Ok(actor::probe::Response::RequestAccepted(state)) => (false, state),
+                Ok(actor::probe::Response::InvalidState(state)) => (true, state),
+                Err(_) => (true, "unknown".to_owned()),
+            };
+            Ok(vec![m.msg.method_return().append2(busy, state)])
+        })
+        .outarg::<bool, _>("busy")
+        .outarg::<&str, _>("current_state")
+        .inarg::<&str, _>("server_address")
+}
+
+fn local_install_method(
+    factory: &Factory<MTFn>,
+    addr: Arc<Addr<actor::Machine>>,
+) -> dbus::tree::Method<MTFn, ()> {
+    // Local/remote install reuse the same `actor::*::Request` shapes
+    // the HTTP gateway already builds from `http_api::message::{local_install, remote_install}`.
+    factory
+        .method("LocalInstall", (), move |m| {
+            let file = m.msg.read1::<&str>().unwrap_or_default().to_owned();
+            let response = addr.send(actor::local_install::Request(file)).wait();
+            let message = match response {
+                Ok(actor::local_install::Response::RequestAccepted) => "request accepted",
+                Ok(actor::local_install::Response::InvalidState) => {
+                    "there is no state to install"
+                }
+                Err(_) => "internal error",
+            };
+            Ok(vec![m.msg.method_return().append1(message)])
+        })
+        .outarg::<&str, _>("message")
+        .inarg::<&str, _>("file")
+}
+
+fn remote_install_method(
+    factory: &Factory<MTFn>,
+    addr: Arc<Addr<actor::Machine>>,
+) -> dbus::tree::Method<MTFn, ()> {
+    factory
+        .method("RemoteInstall", (), move |m| {
+            let url = m.msg.read1::<&str>().unwrap_or_default().to_owned();
+            let response = addr.send(actor::remote_install::Request(url)).wait();
+            let message = match response {
+                Ok(actor::remote_install::Response::RequestAccepted) => "request accepted",
+                Ok(actor::remote_install::Response::InvalidState) => {
+                    "there is no state to install"
+                }
+                Err(_) => "internal error",
+            };
+            Ok(vec![m.msg.method_return().append1(message)])
+        })
+        .outarg::<&str, _>("message")
+        .inarg::<&str, _>("url")
+}
+
+fn state_method(
+    factory: &Factory<MTFn>,
+    addr: Arc<Addr<actor::Machine>>,
+) -> dbus::tree::Method<MTFn, ()> {
+    factory
+        .method("State", (), move |m| {
+            let info = addr.send(actor::info::Request).wait();
+            let state = info.map(|i| i.state).unwrap_or_else(|_| "unknown".to_owned());
+            Ok(vec![m.msg.method_return().append1(state)])
+        })
+        .outarg::<&str, _>("current_state")
+}
+
+fn abort_download_method(
+    factory: &Factory<MTFn>,
+    addr: Arc<Addr<actor::Machine>>,
+) -> dbus::tree::Method<MTFn, ()> {
+    factory
+        .method("AbortDownload", (), move |m| {
+            let response = addr.send(actor::download_abort::Request).wait();
+            let message = match response {
+                Ok(actor::download_abort::Response::RequestAccepted) => {
+                    "request accepted, download aborted"
+                }
+                Ok(actor::download_abort::Response::InvalidState) => {
+                    "there is no download to be aborted"
+                }
+                Err(_) => "internal error",
+            };
+            Ok(vec![m.msg.method_return().append1(message)])
+        })
+        .outarg::<&str, _>("message")
+}
+
+fn log_method(
+    factory: &Factory<MTFn>,
+    _addr: Arc<Addr<actor::Machine>>,
+) -> dbus::tree::Method<MTFn, ()> {
+    factory
+        .method("Log", (), move |m| {
+            let entries = serde_json::to_string(&crate::logger::buffer()).unwrap_or_default();
+            Ok(vec![m.msg.method_return().append1(entries)])
+        })
+        .outarg::<&str, _>("entries_json")
+}
@@ -10,6 +10,8 @@ use serde::Serialize;
 use serde_json::json;
 
 pub fn configure(cfg: &mut web::RouterConfig, addr: Addr<actor::Machine>) {
+    crate::ws_api::configure(cfg, addr.clone());
+
     cfg.data(API::new(addr))
         .route("/info", web::get().to(API::info))
         .route("/log", web::get().to(API::log))
@@ -0,0 +1,101 @@
+// Copyright (C) 2019 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Delegates installing/removing a software module to an external
+//! executable discovered at runtime, instead of baking every
+//! package-type handler into this crate.
+//!
+//! A plugin is a binary named after the package type it supports
+//! (e.g. a binary called `deb` handles objects whose `"mode"` is
+//! `"deb"`), invoked with one of the subcommands below. Its stdout and
+//! stderr are captured into the same log buffer `crate::logger::buffer()`
+//! already collects, so plugin output shows up alongside the rest of
+//! the update log.
+
+use easy_process;
+use std::path::Path;
+
+/// Operations a package-type plugin must support. The `Install` state
+/// groups the objects of an update package by handler type and routes
+/// each group to the matching plugin.
+pub(crate) trait Plugin {
+    fn prepare(&self) -> Result<(), failure::Error>;
+    fn install(&self, module: &str, file: &Path) -> Result<(), failure::Error>;
+    fn remove(&self, module: &str) -> Result<(), failure::Error>;
+    fn update_list(&self, modules: &[String]) -> Result<(), failure::Error>;
+    fn finalize(&self) -> Result<(), failure::Error>;
+    fn list(&self) -> Result<Vec<String>, failure::Error>;
+    fn version(&self) -> Result<String, failure::Error>;
+}
+
+/// A plugin backed by an external executable named after the package
+/// type it handles, e.g. `ExternalPlugin::new("deb")` shells out to a
+/// binary called `deb` on `PATH`.
+pub(crate) struct ExternalPlugin {
+    package_type: String,
+}
+
+impl ExternalPlugin {
+    pub(crate) fn new(package_type: &str) -> Self {
+        Self { package_type: package_type.to_owned() }
+    }
+
+    fn run(&self, args: &[&str]) -> Result<String, failure::Error> {
+        let cmd = format!("{} {}", &self.package_type, args.join(" "));
+        let output = easy_process::run(&cmd)?;
+
+        let buffer = crate::logger::buffer();
+        let mut buffer = buffer.lock().unwrap();
+        for line in output.stdout.lines().chain(output.stderr.lines()) {
+            buffer.push(line.to_owned());
+        }
+
+        Ok(output.stdout)
+    }
+}
+
+impl Plugin for ExternalPlugin {
+    fn prepare(&self) -> Result<(), failure::Error> {
+        self.run(&["prepare"]).map(|_| ())
+    }
+
+    fn install(&self, module: &str, file: &Path) -> Result<(), failure::Error> {
+        self.run(&["install", module, "--file", &file.to_string_lossy()]).map(|_| ())
+    }
+
+    fn remove(&self, module: &str) -> Result<(), failure::Error> {
+        self.run(&["remove", module]).map(|_| ())
+    }
+
+    fn update_list(&self, modules: &[String]) -> Result<(), failure::Error> {
+        let mut args = vec!["update-list"];
+        args.extend(modules.iter().map(String::as_str));
+        self.run(&args).map(|_| ())
+    }
+
+    fn finalize(&self) -> Result<(), failure::Error> {
+        self.run(&["finalize"]).map(|_| ())
+    }
+
+    fn list(&self) -> Result<Vec<String>, failure::Error> {
+        Ok(self.run(&["list"])?.lines().map(str::to_owned).collect())
+    }
+
+    fn version(&self) -> Result<String, failure::Error> {
+        Ok(self.run(&["version"])?.trim().to_owned())
+    }
+}
+
+/// Groups object sha256sums by the package-type handler that should
+/// install them, so the `Download`→`Install` transition can route each
+/// group to its matching plugin.
+pub(crate) fn group_by_type<'a>(
+    objects: &'a [(String, String)],
+) -> std::collections::HashMap<&'a str, Vec<&'a str>> {
+    let mut groups: std::collections::HashMap<&str, Vec<&str>> = std::collections::HashMap::new();
+    for (package_type, sha256sum) in objects {
+        groups.entry(package_type.as_str()).or_default().push(sha256sum.as_str());
+    }
+    groups
+}
@@ -0,0 +1,123 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Structured, locally-accumulated telemetry for the state machine,
+//! distinct from both the human-oriented log and the state names
+//! `client::Api::report` posts back to the server. A `MetricsReporter`
+//! is given every `Event` as it happens; `run()` wires in
+//! `NoopMetricsReporter` by default, and `run_with_metrics_reporter()`
+//! lets a caller plug in something that actually persists them (e.g.
+//! `JsonLinesMetricsReporter`).
+
+use serde::Serialize;
+use std::time::Duration;
+
+/// A structured event emitted by the state machine as an update check
+/// progresses.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// A probe to the server has started.
+    UpdateCheckStarted,
+    /// A probe to the server completed successfully (regardless of
+    /// whether an update was available).
+    UpdateCheckSucceeded,
+    /// A probe to the server failed outright (ran out of retries).
+    UpdateCheckFailed { reason: UpdateCheckFailureReason },
+    /// Time spent handling a single state, from entry to the next
+    /// transition.
+    StateDuration { state: &'static str, duration: Duration },
+    /// Average throughput observed while downloading an update
+    /// package.
+    DownloadThroughput { bytes_per_second: f64 },
+    /// Whether the device came back up in the installation set it
+    /// rebooted into.
+    RebootOutcome { succeeded: bool },
+}
+
+/// Why an `Event::UpdateCheckFailed` happened.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum UpdateCheckFailureReason {
+    /// The request never reached the server (DNS, connect, timeout...).
+    Network { message: String },
+    /// The server answered with a 4xx or 5xx status, e.g. the 501
+    /// returned by a misconfigured update server.
+    Server { status: u16 },
+    /// The server's response didn't satisfy `compatible_with`.
+    CheckRequirementsFailed { message: String },
+}
+
+/// Receives `Event`s as the state machine produces them. Implementors
+/// must not let reporting failures affect the update itself, so
+/// `report` has no return value; a reporter that needs to signal a
+/// failure should log it and move on.
+pub trait MetricsReporter {
+    fn report(&self, event: Event);
+}
+
+/// The default reporter: discards every event. Used by `run()` when no
+/// reporter is configured.
+#[derive(Debug, Default)]
+pub struct NoopMetricsReporter;
+
+impl MetricsReporter for NoopMetricsReporter {
+    fn report(&self, _event: Event) {}
+}
+
+/// Appends each event as one JSON object per line to a file, so a
+/// device accumulates a locally-inspectable history of update-check
+/// outcomes and timings across runs.
+#[derive(Debug)]
+pub struct JsonLinesMetricsReporter {
+    path: std::path::PathBuf,
+}
+
+impl JsonLinesMetricsReporter {
+    pub fn new(path: impl Into<std::path::PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    fn try_report(&self, event: &Event) -> std::io::Result<()> {
+        use std::io::Write;
+
+        let line = serde_json::json!({
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+            "event": event,
+        });
+
+        writeln!(
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?,
+            "{}",
+            line
+        )
+    }
+}
+
+impl MetricsReporter for JsonLinesMetricsReporter {
+    fn report(&self, event: Event) {
+        if let Err(e) = self.try_report(&event) {
+            error!("failed to write metrics event to {:?}: {}", self.path, e);
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref REPORTER: std::sync::Mutex<Box<dyn MetricsReporter + Send>> =
+        std::sync::Mutex::new(Box::new(NoopMetricsReporter));
+}
+
+/// Replaces the globally-installed reporter. Called once by `run()`
+/// (or `run_with_metrics_reporter()`) before the state machine starts.
+pub(crate) fn set_reporter(reporter: Box<dyn MetricsReporter + Send>) {
+    *REPORTER.lock().unwrap() = reporter;
+}
+
+/// Reports `event` to the globally-installed reporter.
+pub(crate) fn report(event: Event) {
+    REPORTER.lock().unwrap().report(event);
+}
@@ -0,0 +1,141 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Peer-to-peer object fetching over the LAN.
+//!
+//! When `network.p2p` is enabled, a device both advertises the objects
+//! it already has (`advertise`) and, before falling back to
+//! `network.server_address`, asks a handful of discovered peers for a
+//! missing object (`fetch_from_peers`). Discovery and the fetch itself
+//! are bounded by `network.p2p_max_peers`/`network.p2p_timeout_ms`, so a
+//! LAN with no peers never stalls the existing server path.
+
+use crypto_hash::{hex_digest, Algorithm};
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+const SERVICE_TYPE: &str = "_updatehub-p2p._tcp.local.";
+
+/// Advertises `download_dir` on the LAN and serves its contents, keyed
+/// by sha256sum, to other devices running the same service. Runs in a
+/// background thread for the lifetime of the process.
+pub(crate) fn advertise(download_dir: std::path::PathBuf, port: u16) -> Result<(), failure::Error> {
+    let daemon = mdns_sd::ServiceDaemon::new()?;
+    let hostname = format!("{}.local.", hostname::get()?.to_string_lossy());
+    let service = mdns_sd::ServiceInfo::new(
+        SERVICE_TYPE,
+        &hostname,
+        &hostname,
+        "",
+        port,
+        None,
+    )?;
+    daemon.register(service)?;
+
+    let server = tiny_http::Server::http(format!("0.0.0.0:{}", port))
+        .map_err(|e| failure::format_err!("failed to start p2p server: {}", e))?;
+    std::thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let object = request
+                .url()
+                .trim_start_matches('/')
+                .trim_start_matches("objects/")
+                .to_owned();
+            let path = download_dir.join(&object);
+
+            let response = match std::fs::File::open(&path) {
+                Ok(file) => tiny_http::Response::from_file(file),
+                Err(_) => {
+                    let _ = request.respond(tiny_http::Response::empty(404));
+                    continue;
+                }
+            };
+            let _ = request.respond(response);
+        }
+    });
+
+    Ok(())
+}
+
+/// Looks for up to `max_peers` peers advertising `object` and downloads
+/// it from the first one that answers with matching content, writing it
+/// to `download_dir.join(object)`. The whole discovery + fetch attempt
+/// is bounded by `timeout`; returns `true` only if the object was
+/// fetched and verified.
+pub(crate) fn fetch_from_peers(
+    object: &str,
+    download_dir: &Path,
+    max_peers: usize,
+    timeout: Duration,
+) -> bool {
+    let deadline = Instant::now() + timeout;
+
+    let daemon = match mdns_sd::ServiceDaemon::new() {
+        Ok(daemon) => daemon,
+        Err(_) => return false,
+    };
+    let receiver = match daemon.browse(SERVICE_TYPE) {
+        Ok(receiver) => receiver,
+        Err(_) => return false,
+    };
+
+    let mut tried = 0;
+    while tried < max_peers {
+        let remaining = match deadline.checked_duration_since(Instant::now()) {
+            Some(remaining) if !remaining.is_zero() => remaining,
+            _ => break,
+        };
+
+        let event = match receiver.recv_timeout(remaining) {
+            Ok(event) => event,
+            Err(_) => break,
+        };
+
+        let info = match event {
+            mdns_sd::ServiceEvent::ServiceResolved(info) => info,
+            _ => continue,
+        };
+        let addresses = info.get_addresses();
+        let addr = match addresses.iter().next() {
+            Some(addr) => addr,
+            None => continue,
+        };
+
+        tried += 1;
+        if fetch_from_peer(*addr, info.get_port(), object, download_dir).is_ok() {
+            return true;
+        }
+    }
+
+    false
+}
+
+fn fetch_from_peer(
+    addr: std::net::Ipv4Addr,
+    port: u16,
+    object: &str,
+    download_dir: &Path,
+) -> Result<(), failure::Error> {
+    let url = format!("http://{}:{}/objects/{}", addr, port, object);
+    let body = reqwest::Client::new()
+        .get(&url)
+        .timeout(Duration::from_secs(2))
+        .send()?
+        .error_for_status()?
+        .bytes()?
+        .to_vec();
+
+    if hex_digest(Algorithm::SHA256, &body) != object {
+        bail!("peer {} returned content that does not match {}", addr, object);
+    }
+
+    if !download_dir.exists() {
+        std::fs::create_dir_all(download_dir)?;
+    }
+    std::fs::write(download_dir.join(object), body)?;
+
+    Ok(())
+}
@@ -22,11 +22,14 @@ use mockito;
 #[derive(Debug, Default, PartialEq, Deserialize, Serialize, Clone)]
 #[serde(rename_all = "PascalCase")]
 pub struct Settings {
+    #[serde(default)]
+    pub(crate) auth: Auth,
     pub(crate) firmware: Firmware,
     pub(crate) network: Network,
     pub(crate) polling: Polling,
     pub(crate) storage: Storage,
     pub(crate) update: Update,
+    pub(crate) installation_set: InstallationSet,
 }
 
 impl Settings {
@@ -109,6 +112,20 @@ pub struct Polling {
     /// Defines if automatic polling is enabled or not. By default it
     /// is enabled.
     pub enabled: bool,
+    /// Upper bound the exponential backoff applied after consecutive
+    /// failed checks can grow the polling interval to. By default, 1
+    /// day (i.e. never longer than a single unbacked-off interval).
+    #[serde(
+        deserialize_with = "de::duration_from_str",
+        serialize_with = "ser::duration_to_int"
+    )]
+    pub max_interval: Duration,
+    /// Fraction by which the backed-off polling interval is randomly
+    /// fuzzed (uniformly, both shorter and longer), so a fleet that
+    /// started failing in lock-step doesn't retry in lock-step too. By
+    /// default, 0.25 (i.e. +/-25%).
+    #[serde(deserialize_with = "de::f32_from_str")]
+    pub fuzz: f32,
 }
 
 impl Default for Polling {
@@ -116,6 +133,8 @@ impl Default for Polling {
         Self {
             interval: Duration::days(1),
             enabled: true,
+            max_interval: Duration::days(1),
+            fuzz: 0.25,
         }
     }
 }
@@ -169,6 +188,76 @@ impl Default for Update {
 #[serde(rename_all = "PascalCase")]
 pub struct Network {
     pub server_address: String,
+    /// Maximum number of objects downloaded simultaneously. By
+    /// default, up to 4 objects are fetched in parallel.
+    #[serde(deserialize_with = "de::u64_from_str")]
+    pub download_concurrency: u64,
+    /// Exposes the control API (probe, local/remote install, state,
+    /// abort download, log) over the D-Bus system bus, alongside the
+    /// HTTP API. Disabled by default.
+    #[serde(deserialize_with = "de::bool_from_str")]
+    pub dbus_gateway: bool,
+    /// Before fetching an object from `server_address`, look for peers
+    /// on the LAN that already have it (see `crate::p2p`). Disabled by
+    /// default.
+    #[serde(deserialize_with = "de::bool_from_str")]
+    pub p2p: bool,
+    /// Maximum number of discovered peers queried for a given object
+    /// before falling back to `server_address`.
+    #[serde(deserialize_with = "de::u64_from_str")]
+    pub p2p_max_peers: u64,
+    /// Budget, in milliseconds, for the whole peer discovery +
+    /// fetch attempt, so a LAN with no peers never stalls the
+    /// existing server path.
+    #[serde(deserialize_with = "de::u64_from_str")]
+    pub p2p_timeout_ms: u64,
+    /// Base delay for the exponential backoff applied between failed
+    /// probe attempts. By default, 1 second.
+    #[serde(
+        deserialize_with = "de::duration_from_str",
+        serialize_with = "ser::duration_to_int"
+    )]
+    pub backoff_base: Duration,
+    /// Upper bound for the exponential backoff delay between failed
+    /// probe attempts, before jitter is applied. By default, 1 minute.
+    #[serde(
+        deserialize_with = "de::duration_from_str",
+        serialize_with = "ser::duration_to_int"
+    )]
+    pub backoff_cap: Duration,
+    /// Maximum number of consecutive probe failures before giving up
+    /// and moving back to `Idle` instead of retrying forever. Unset
+    /// (the default) retries indefinitely.
+    pub max_retries: Option<u64>,
+    /// Maximum number of times a dropped connection or transient
+    /// server error is retried while downloading a single object
+    /// before giving up on the whole update. Unlike `max_retries`,
+    /// this is always bounded: a flaky link resumes from the byte
+    /// offset last persisted to `RuntimeSettings` rather than starting
+    /// the object over. By default, 3 retries.
+    #[serde(deserialize_with = "de::u64_from_str")]
+    pub download_retries: u64,
+    /// Which `UpdateService` backend to probe/download/report through.
+    /// By default, the updatehub protocol.
+    pub protocol: UpdateProtocol,
+    /// Exposes the control API (probe, local/remote install, state,
+    /// abort download, log) as line-delimited JSON over a Unix domain
+    /// socket at this path, alongside the HTTP API. Unset (the
+    /// default) disables this gateway.
+    pub unix_socket_gateway: Option<PathBuf>,
+}
+
+/// Selects the `UpdateService` implementation `State<Probe>` talks to.
+#[derive(Debug, Deserialize, PartialEq, Serialize, Clone, Copy)]
+pub enum UpdateProtocol {
+    UpdateHub,
+    Omaha,
+}
+
+impl Default for UpdateProtocol {
+    fn default() -> Self {
+        UpdateProtocol::UpdateHub
+    }
 }
 
 impl Default for Network {
@@ -178,10 +267,38 @@ impl Default for Network {
         #[cfg(not(test))]
         let server_address = "https://api.updatehub.io".to_string();
 
-        Self { server_address }
+        Self {
+            server_address,
+            download_concurrency: 4,
+            dbus_gateway: false,
+            p2p: false,
+            p2p_max_peers: 4,
+            p2p_timeout_ms: 500,
+            backoff_base: Duration::seconds(1),
+            backoff_cap: Duration::minutes(1),
+            max_retries: None,
+            download_retries: 3,
+            protocol: UpdateProtocol::UpdateHub,
+            unix_socket_gateway: None,
+        }
     }
 }
 
+/// Credentials used to authenticate against a server that requires
+/// per-device authentication rather than assuming an open endpoint.
+/// Leaving every field empty (the default) disables authentication.
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct Auth {
+    /// Static bearer token, used as-is if set.
+    pub token: Option<String>,
+    /// OAuth2 client-credentials grant: exchanged at `TokenUrl` for an
+    /// access token that is cached and transparently refreshed.
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub token_url: Option<String>,
+}
+
 #[derive(Debug, Deserialize, PartialEq, Serialize, Clone)]
 #[serde(rename_all = "PascalCase")]
 pub struct Firmware {
@@ -196,12 +313,35 @@ impl Default for Firmware {
     }
 }
 
+/// Device node each boot slot's objects are written to, and the
+/// bootloader environment variable `firmware::installation_set` flips
+/// to switch which one boots next. See `crate::states::install`.
+#[derive(Debug, Deserialize, PartialEq, Serialize, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct InstallationSet {
+    pub system0: PathBuf,
+    pub system1: PathBuf,
+    pub bootloader_env_var: String,
+}
+
+impl Default for InstallationSet {
+    fn default() -> Self {
+        Self {
+            system0: "/dev/mmcblk0p1".into(),
+            system1: "/dev/mmcblk0p2".into(),
+            bootloader_env_var: "updatehub_active".into(),
+        }
+    }
+}
+
 #[test]
 fn ok() {
     let ini = r"
 [Polling]
 Interval=60s
 Enabled=false
+MaxInterval=120s
+Fuzz=0.1
 
 [Storage]
 ReadOnly=true
@@ -213,15 +353,34 @@ SupportedInstallModes=mode1,mode2
 
 [Network]
 ServerAddress=http://localhost
+DownloadConcurrency=8
+DbusGateway=true
+P2p=true
+P2pMaxPeers=2
+P2pTimeoutMs=250
+BackoffBase=2s
+BackoffCap=120s
+MaxRetries=5
+DownloadRetries=10
+Protocol=UpdateHub
+UnixSocketGateway=/run/updatehub.sock
 
 [Firmware]
 MetadataPath=/tmp/metadata
+
+[InstallationSet]
+System0=/dev/mmcblk0p3
+System1=/dev/mmcblk0p4
+BootloaderEnvVar=active_set
 ";
 
     let expected = Settings {
+        auth: Auth::default(),
         polling: Polling {
             interval: Duration::seconds(60),
             enabled: false,
+            max_interval: Duration::seconds(120),
+            fuzz: 0.1,
         },
         storage: Storage {
             read_only: true,
@@ -233,10 +392,26 @@ MetadataPath=/tmp/metadata
         },
         network: Network {
             server_address: "http://localhost".into(),
+            download_concurrency: 8,
+            dbus_gateway: true,
+            p2p: true,
+            p2p_max_peers: 2,
+            p2p_timeout_ms: 250,
+            backoff_base: Duration::seconds(2),
+            backoff_cap: Duration::seconds(120),
+            max_retries: Some(5),
+            download_retries: 10,
+            protocol: UpdateProtocol::UpdateHub,
+            unix_socket_gateway: Some("/run/updatehub.sock".into()),
         },
         firmware: Firmware {
             metadata_path: "/tmp/metadata".into(),
         },
+        installation_set: InstallationSet {
+            system0: "/dev/mmcblk0p3".into(),
+            system1: "/dev/mmcblk0p4".into(),
+            bootloader_env_var: "active_set".into(),
+        },
     };
 
     assert_eq!(
@@ -288,6 +463,8 @@ SupportedInstallModes=mode1,mode2
 
 [Network]
 ServerAddress=localhost
+DownloadConcurrency=4
+DbusGateway=false
 
 [Firmware]
 MetadataPath=/tmp/metadata
@@ -302,9 +479,12 @@ fn default() {
     settings.network.server_address = "https://api.updatehub.io".to_string();
 
     let expected = Settings {
+        auth: Auth::default(),
         polling: Polling {
             interval: Duration::days(1),
             enabled: true,
+            max_interval: Duration::days(1),
+            fuzz: 0.25,
         },
         storage: Storage {
             read_only: false,
@@ -321,10 +501,26 @@ fn default() {
         },
         network: Network {
             server_address: "https://api.updatehub.io".to_string(),
+            download_concurrency: 4,
+            dbus_gateway: false,
+            p2p: false,
+            p2p_max_peers: 4,
+            p2p_timeout_ms: 500,
+            backoff_base: Duration::seconds(1),
+            backoff_cap: Duration::minutes(1),
+            max_retries: None,
+            download_retries: 3,
+            protocol: UpdateProtocol::UpdateHub,
+            unix_socket_gateway: None,
         },
         firmware: Firmware {
             metadata_path: "/usr/share/updatehub".into(),
         },
+        installation_set: InstallationSet {
+            system0: "/dev/mmcblk0p1".into(),
+            system1: "/dev/mmcblk0p2".into(),
+            bootloader_env_var: "updatehub_active".into(),
+        },
     };
 
     assert_eq!(Some(settings), Some(expected));
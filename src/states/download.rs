@@ -7,8 +7,13 @@ use Result;
 
 use client::Api;
 use firmware::installation_set;
+use metrics::{self, Event};
 use states::{Idle, Install, State, StateChangeImpl, StateMachine};
-use std::fs;
+use std::{
+    collections::HashMap,
+    fs,
+    sync::{atomic::{AtomicBool, Ordering}, Mutex},
+};
 use update_package::{ObjectStatus, UpdatePackage};
 use walkdir::WalkDir;
 
@@ -25,7 +30,7 @@ impl StateChangeImpl for State<Download> {
         Some("download")
     }
 
-    fn handle(self) -> Result<StateMachine> {
+    fn handle(mut self) -> Result<StateMachine> {
         let installation_set = installation_set::inactive()?;
 
         // Prune left over from previous installations
@@ -57,19 +62,166 @@ impl StateChangeImpl for State<Download> {
             fs::remove_file(&self.settings.update.download_dir.join(object.sha256sum()))?;
         }
 
-        // Download the missing or incomplete objects
-        for object in self
-            .state
-            .update_package
-            .filter_objects(&self.settings, installation_set, &ObjectStatus::Missing)
-            .into_iter()
-            .chain(self.state.update_package.filter_objects(
-                &self.settings,
-                installation_set,
-                &ObjectStatus::Incomplete,
-            )) {
-            Api::new(&self.settings, &self.runtime_settings, &self.firmware)
-                .download_object(&self.state.update_package.package_uid(), object.sha256sum())?;
+        // Download the missing or incomplete objects, up to
+        // `network.download_concurrency` objects at a time. Each
+        // object is addressed by its own sha256sum, so two in-flight
+        // downloads never race on the same destination file.
+        let pending = Mutex::new(
+            self.state
+                .update_package
+                .filter_objects(&self.settings, installation_set, &ObjectStatus::Missing)
+                .into_iter()
+                .chain(self.state.update_package.filter_objects(
+                    &self.settings,
+                    installation_set,
+                    &ObjectStatus::Incomplete,
+                ))
+                .collect::<Vec<_>>(),
+        );
+        let aborted = AtomicBool::new(false);
+        let package_uid = self.state.update_package.package_uid();
+        let workers = self.settings.network.download_concurrency.max(1) as usize;
+
+        // Per-object (downloaded, total) pairs, aggregated into a
+        // fleet-wide "object 3/7, 42% overall" progress report as each
+        // worker's callback fires. `total` stays `None` for an object
+        // until its `Content-Length` response header is read.
+        let progress: Mutex<HashMap<String, (u64, Option<u64>)>> = Mutex::new(HashMap::new());
+        let report_progress = |progress: &HashMap<String, (u64, Option<u64>)>| {
+            let bytes_downloaded = progress.values().map(|(downloaded, _)| downloaded).sum();
+            let download_size = progress
+                .values()
+                .map(|(_, total)| *total)
+                .collect::<Option<Vec<u64>>>()
+                .map(|totals| totals.into_iter().sum());
+
+            let report = ::client::DownloadProgress::new(bytes_downloaded, download_size);
+            let _ = ::client::Api::new(&self.settings.network.server_address).report_with_progress(
+                "downloading",
+                &self.firmware,
+                &package_uid,
+                None,
+                None,
+                Some(report),
+            );
+        };
+
+        let started_at = std::time::Instant::now();
+
+        // Shared across workers so a checkpoint persisted by one
+        // object's download survives a later object's failure, and so
+        // `self.runtime_settings` reflects every worker's progress once
+        // the scope below exits.
+        let runtime_settings = Mutex::new(self.runtime_settings);
+        let download_retries = self.settings.network.download_retries;
+
+        crossbeam_utils::thread::scope(|scope| {
+            let handles: Vec<_> = (0..workers)
+                .map(|_| {
+                    scope.spawn(|_| -> Result<()> {
+                        loop {
+                            if aborted.load(Ordering::SeqCst) {
+                                return Ok(());
+                            }
+
+                            let object = match pending.lock().unwrap().pop() {
+                                Some(object) => object,
+                                None => return Ok(()),
+                            };
+
+                            let sha256sum = object.sha256sum().to_owned();
+
+                            if self.settings.network.p2p
+                                && ::p2p::fetch_from_peers(
+                                    &sha256sum,
+                                    &self.settings.update.download_dir,
+                                    self.settings.network.p2p_max_peers as usize,
+                                    std::time::Duration::from_millis(self.settings.network.p2p_timeout_ms),
+                                )
+                            {
+                                continue;
+                            }
+
+                            // If the server advertised this object as a
+                            // delta against one we already hold, try
+                            // fetching the (much smaller) patch first;
+                            // fall back to a full download on any
+                            // failure to fetch or reconstruct it.
+                            if let Some(delta) = object.delta() {
+                                if Api::new(&self.settings, &*runtime_settings.lock().unwrap(), &self.firmware)
+                                    .download_delta_object(&package_uid, &sha256sum, delta)
+                                    .unwrap_or(false)
+                                {
+                                    continue;
+                                }
+                            }
+
+                            // A dropped connection only ends this one
+                            // attempt; the next attempt resumes from
+                            // the checkpoint `download_object_with_progress`
+                            // just persisted instead of starting the
+                            // object over, so a flaky link costs a
+                            // retry rather than the whole transfer.
+                            let mut attempt = 0;
+                            let result = loop {
+                                let result = Api::new(&self.settings, &*runtime_settings.lock().unwrap(), &self.firmware)
+                                    .download_object_with_progress(
+                                        &package_uid,
+                                        object.sha256sum(),
+                                        &runtime_settings,
+                                        |downloaded, total| {
+                                            let mut progress = progress.lock().unwrap();
+                                            progress.insert(sha256sum.clone(), (downloaded, total));
+                                            report_progress(&progress);
+                                        },
+                                    );
+
+                                match result {
+                                    Ok(()) => break Ok(()),
+                                    Err(e) if attempt < download_retries => {
+                                        attempt += 1;
+                                        error!(
+                                            "Failed to download object {} (attempt {}/{}): {}",
+                                            &sha256sum, attempt, download_retries, e
+                                        );
+                                        std::thread::sleep(
+                                            self.settings
+                                                .network
+                                                .backoff_base
+                                                .to_std()
+                                                .unwrap_or_default()
+                                                .saturating_mul(2u32.saturating_pow(attempt as u32)),
+                                        );
+                                    }
+                                    Err(e) => break Err(e),
+                                }
+                            };
+
+                            if let Err(e) = result {
+                                aborted.store(true, Ordering::SeqCst);
+                                return Err(e);
+                            }
+                        }
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|h| h.join().expect("download worker panicked"))
+                .collect::<Result<Vec<()>>>()
+                .map(|_| ())
+        })
+        .expect("download worker thread panicked")?;
+
+        self.runtime_settings = runtime_settings.into_inner().expect("runtime settings mutex poisoned");
+
+        let elapsed = started_at.elapsed();
+        let bytes_downloaded: u64 = progress.lock().unwrap().values().map(|(downloaded, _)| downloaded).sum();
+        if elapsed.as_secs() > 0 || elapsed.subsec_millis() > 0 {
+            metrics::report(Event::DownloadThroughput {
+                bytes_per_second: bytes_downloaded as f64 / elapsed.as_secs_f64(),
+            });
         }
 
         if self
@@ -6,7 +6,7 @@ use super::{
     actor::{download_abort, probe},
     Idle, ProgressReporter, Reboot, State, StateChangeImpl, StateMachine, TransitionCallback,
 };
-use crate::update_package::UpdatePackage;
+use crate::{firmware::installation_set, update_package::UpdatePackage};
 use slog::slog_info;
 use slog_scope::info;
 
@@ -51,8 +51,65 @@ impl StateChangeImpl for State<Install> {
         let package_uid = self.0.update_package.package_uid();
         info!("Installing update: {}", &package_uid);
 
-        // FIXME: Check if A/B install
-        // FIXME: Check InstallIfDifferent
+        // Install into the currently inactive slot, so the active one
+        // stays bootable if anything below fails partway through.
+        let inactive_set = installation_set::inactive()?;
+        std::env::set_var("UH_INSTALLATION_SET", inactive_set.to_string());
+
+        // Route objects whose package type isn't handled in-process to
+        // the matching external plugin, named after the package type
+        // it supports (e.g. a `deb` binary installs `"mode": "deb"`
+        // objects). See `crate::installer` for the plugin contract.
+        let mut operation_results = Vec::new();
+        for (package_type, modules) in
+            crate::installer::group_by_type(&self.0.update_package.external_objects())
+        {
+            let plugin = crate::installer::ExternalPlugin::new(package_type);
+            plugin.prepare()?;
+            plugin.update_list(&modules.iter().map(|m| (*m).to_owned()).collect::<Vec<_>>())?;
+            for module in modules {
+                // Skip objects whose target already holds the content
+                // we'd install (see `update_package::install_if_different`),
+                // which saves a write/flash cycle for deltas where most
+                // objects are unchanged.
+                if self
+                    .0
+                    .update_package
+                    .object_check(module)
+                    .map_or(false, |check| check.already_installed())
+                {
+                    info!("object {} already matches its target, skipping install", module);
+                    operation_results.push(::client::OperationResult::skipped(module));
+                    continue;
+                }
+
+                plugin.install(module, &self.0.update_package.download_dir().join(module))?;
+            }
+            plugin.finalize()?;
+        }
+
+        if !operation_results.is_empty() {
+            let _ = ::client::Api::with_auth(
+                &shared_state!().settings.network.server_address,
+                &shared_state!().settings.auth,
+            )
+            .report_operations(
+                "installing",
+                &shared_state!().firmware,
+                &package_uid,
+                &operation_results,
+            );
+        }
+
+        // Flip the pending/upgrade slot to the one we just installed
+        // into, and reset the boot-attempt counter so `run` gives the
+        // new slot a fresh set of attempts to prove itself after
+        // reboot, instead of inheriting a stale count from a previous
+        // install.
+        shared_state_mut!()
+            .runtime_settings
+            .set_upgrade_to_installation_set(inactive_set)?;
+        shared_state_mut!().runtime_settings.clear_boot_attempts()?;
 
         // Ensure we do a probe as soon as possible so full update
         // cycle can be finished.
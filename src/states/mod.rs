@@ -12,18 +12,25 @@ mod poll;
 mod probe;
 mod reboot;
 mod transition;
+mod validate_boot;
 
 use Result;
 
 use self::{
     download::Download, idle::Idle, install::Install, park::Park, poll::Poll, probe::Probe,
-    reboot::Reboot,
+    reboot::Reboot, validate_boot::ValidateBoot,
 };
 
 use firmware::Metadata;
+use metrics::{self, Event};
 use runtime_settings::RuntimeSettings;
 use settings::Settings;
 
+/// How many boots we give a newly-installed slot to reach `run` and
+/// commit itself before giving up and letting the bootloader's own
+/// fallback keep booting the previous slot.
+const MAX_BOOT_ATTEMPTS: u32 = 3;
+
 trait StateChangeImpl {
     fn handle(self) -> Result<StateMachine>;
 }
@@ -58,6 +65,7 @@ enum StateMachine {
     Download(State<Download>),
     Install(State<Install>),
     Reboot(State<Reboot>),
+    ValidateBoot(State<ValidateBoot>),
 }
 
 impl<S> State<S>
@@ -85,13 +93,14 @@ where
 {
     fn handle_and_report_progress(self) -> Result<StateMachine> {
         let server = self.settings.network.server_address.clone();
+        let auth = self.settings.auth.clone();
         let firmware = self.firmware.clone();
         let package_uid = self.package_uid().clone();
         let enter_state = self.report_enter_state_name();
         let leave_state = self.report_leave_state_name();
 
         let report = |state, previous_state, error_message| {
-            ::client::Api::new(&server).report(
+            ::client::Api::with_auth(&server, &auth).report(
                 state,
                 &firmware,
                 &package_uid,
@@ -101,13 +110,24 @@ where
         };
 
         report(enter_state, None, None)?;
-        self.handle()
+        let started_at = std::time::Instant::now();
+        let result = self.handle();
+        metrics::report(Event::StateDuration {
+            state: enter_state,
+            duration: started_at.elapsed(),
+        });
+
+        result
             .and_then(|state| {
                 report(leave_state, None, None)?;
                 Ok(state)
             })
             .or_else(|e| {
                 report("error", Some(enter_state), Some(e.to_string()))?;
+                // This update attempt failed, so back off the next
+                // poll too (see `states::poll`); a successful probe
+                // clears this again.
+                shared_state_mut!().runtime_settings.inc_consecutive_failed_checks()?;
                 Err(e)
             })
     }
@@ -132,6 +152,7 @@ impl StateMachine {
             StateMachine::Download(s) => Ok(s.handle_with_callback_and_report_progress()?),
             StateMachine::Install(s) => Ok(s.handle_with_callback_and_report_progress()?),
             StateMachine::Reboot(s) => Ok(s.handle_with_callback_and_report_progress()?),
+            StateMachine::ValidateBoot(s) => Ok(s.handle()?),
         }
     }
 }
@@ -145,13 +166,14 @@ impl StateMachine {
 /// ```text
 ///           .--------------.
 ///           |              v
-/// Park <- Idle -> Poll -> Probe -> Download -> Install -> Reboot
-///           ^      ^        '          '          '
-///           '      '        '          '          '
-///           '      `--------'          '          '
-///           `---------------'          '          '
-///           `--------------------------'          '
-///           `-------------------------------------'
+/// Park <- Idle -> Poll -> Probe -> Download -> Install -> Reboot -> ValidateBoot
+///           ^      ^        '          '          '                     '
+///           '      '        '          '          '                     '
+///           '      `--------'          '          '                     '
+///           `---------------'          '          '                     '
+///           `--------------------------'          '                     '
+///           `-------------------------------------'                     '
+///           `------------------------------------------------------------'
 /// ```
 ///
 /// # Example
@@ -168,13 +190,40 @@ impl StateMachine {
 /// # }
 /// ```
 pub fn run(settings: Settings) -> Result<()> {
+    run_with_metrics_reporter(settings, Box::new(metrics::NoopMetricsReporter))
+}
+
+/// Same as `run`, but accumulates `metrics::Event`s into `reporter`
+/// instead of discarding them, so a device keeps a local, actionable
+/// history of update-check outcomes and state timings that
+/// `client::Api::report` (which only posts state names back to the
+/// server) doesn't capture.
+pub fn run_with_metrics_reporter(
+    settings: Settings,
+    reporter: Box<dyn metrics::MetricsReporter + Send>,
+) -> Result<()> {
+    metrics::set_reporter(reporter);
+
     let mut runtime_settings = RuntimeSettings::new().load(&settings.storage.runtime_settings)?;
     if !settings.storage.read_only {
         runtime_settings.enable_persistency();
     }
 
     let firmware = Metadata::new(&settings.firmware.metadata_path)?;
-    let mut machine = StateMachine::new(settings, runtime_settings, firmware);
+
+    // If we just rebooted into a freshly installed slot, the first
+    // thing the state machine does is confirm it booted correctly;
+    // otherwise, start idling as usual.
+    let mut machine = if runtime_settings.upgrade_to_installation_set().is_some() {
+        StateMachine::ValidateBoot(State {
+            settings,
+            runtime_settings,
+            firmware,
+            state: ValidateBoot {},
+        })
+    } else {
+        StateMachine::new(settings, runtime_settings, firmware)
+    };
 
     // Iterate over the state machine.
     loop {
@@ -6,6 +6,8 @@ use Result;
 
 use chrono::{DateTime, Duration, Utc};
 use rand::{self, Rng};
+use runtime_settings::RuntimeSettings;
+use settings::Settings;
 use states::{Probe, State, StateChangeImpl, StateMachine};
 use std::{
     sync::{Arc, Condvar, Mutex},
@@ -17,51 +19,125 @@ pub(super) struct Poll {}
 
 create_state_step!(Poll => Probe);
 
-/// Implements the state change for `State<Poll>`.
-///
-/// This state is used to control when to go to the `State<Probe>`.
-impl StateChangeImpl for State<Poll> {
-    fn handle(self) -> Result<StateMachine> {
-        let current_time: DateTime<Utc> = Utc::now();
+/// What `State<Poll>` should do next, as decided by a `PollPolicy`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum CheckTiming {
+    /// Move to `State<Probe>` right away.
+    Now,
+    /// Sleep until this point in time, then move to `State<Probe>`.
+    At(DateTime<Utc>),
+}
 
-        if self.runtime_settings.is_polling_forced() {
+/// Decides when `State<Poll>` should next move to `State<Probe>`,
+/// decoupled from the thread/`Condvar` machinery that actually waits
+/// for that time to arrive, so the decision itself is plain and
+/// testable. `DefaultPollPolicy` reproduces the historical forced-poll
+/// / last-poll-in-future / extra-interval / flat-interval behavior;
+/// operators can inject a different one (e.g. only poll during
+/// maintenance windows, or suppress polling while on battery).
+pub(super) trait PollPolicy {
+    fn next_check_timing(
+        &self,
+        runtime_settings: &RuntimeSettings,
+        settings: &Settings,
+        now: DateTime<Utc>,
+    ) -> CheckTiming;
+}
+
+pub(super) struct DefaultPollPolicy;
+
+impl PollPolicy for DefaultPollPolicy {
+    fn next_check_timing(
+        &self,
+        runtime_settings: &RuntimeSettings,
+        settings: &Settings,
+        now: DateTime<Utc>,
+    ) -> CheckTiming {
+        if runtime_settings.is_polling_forced() {
             debug!("Moving to Probe state as soon as possible.");
-            return Ok(StateMachine::Probe(self.into()));
+            return CheckTiming::Now;
         }
 
-        let last_poll = self.runtime_settings.last_polling().unwrap_or_else(|| {
+        let last_poll = runtime_settings.last_polling().unwrap_or_else(|| {
             // When no polling has been done before, we choose an
             // offset between current time and the intended polling
             // interval and use it as last_poll
             let mut rnd = rand::thread_rng();
-            let interval = self.settings.polling.interval.num_seconds();
+            let interval = settings.polling.interval.num_seconds();
             let offset = Duration::seconds(rnd.gen_range(0, interval));
 
-            current_time + offset
+            now + offset
         });
 
-        if last_poll > current_time {
+        if last_poll > now {
             info!("Forcing to Probe state as last polling seems to happened in future.");
-            return Ok(StateMachine::Probe(self.into()));
+            return CheckTiming::Now;
         }
 
-        let extra_interval = self.runtime_settings.polling_extra_interval();
-        if last_poll + extra_interval.unwrap_or_else(|| Duration::seconds(0)) < current_time {
+        let extra_interval = runtime_settings.polling_extra_interval();
+        if last_poll + extra_interval.unwrap_or_else(|| Duration::seconds(0)) < now {
             debug!("Moving to Probe state as the polling's due extra interval.");
-            return Ok(StateMachine::Probe(self.into()));
+            return CheckTiming::Now;
         }
 
-        let probe = Arc::new((Mutex::new(()), Condvar::new()));
-        let probe2 = probe.clone();
-        let interval = self.settings.polling.interval;
-        thread::spawn(move || {
-            let (_, ref cvar) = *probe2;
-            thread::sleep(interval.to_std().unwrap());
-            cvar.notify_one();
-        });
+        if let Some(interval) = runtime_settings.server_requested_interval() {
+            debug!("Using the server-requested interval for the next poll.");
+            return CheckTiming::At(last_poll + interval);
+        }
+
+        let failures = runtime_settings.consecutive_failed_checks();
+        CheckTiming::At(last_poll + backoff_interval(settings, failures))
+    }
+}
+
+/// The interval to wait for the next check, given how many consecutive
+/// ones already failed: `interval * 2^failures`, capped at
+/// `polling.max_interval`, then fuzzed by a uniform factor in
+/// `[1 - fuzz, 1 + fuzz]` so a fleet that started failing together
+/// doesn't hammer the server together on every retry too.
+fn backoff_interval(settings: &Settings, failures: u32) -> Duration {
+    let delay = settings
+        .polling
+        .interval
+        .to_std()
+        .unwrap_or_default()
+        .saturating_mul(2u32.saturating_pow(failures))
+        .min(settings.polling.max_interval.to_std().unwrap_or_default());
+
+    let fuzz = settings.polling.fuzz.max(0.0).min(1.0);
+    if fuzz <= 0.0 {
+        return Duration::from_std(delay).unwrap_or_else(|_| Duration::seconds(0));
+    }
+
+    let factor = rand::thread_rng().gen_range(1.0 - fuzz, 1.0 + fuzz);
+    Duration::from_std(delay.mul_f64(f64::from(factor))).unwrap_or_else(|_| Duration::seconds(0))
+}
 
-        let (ref lock, ref cvar) = *probe;
-        let _ = cvar.wait(lock.lock().unwrap());
+/// Implements the state change for `State<Poll>`.
+///
+/// This state is used to control when to go to the `State<Probe>`.
+impl StateChangeImpl for State<Poll> {
+    fn handle(self) -> Result<StateMachine> {
+        let timing =
+            DefaultPollPolicy.next_check_timing(&self.runtime_settings, &self.settings, Utc::now());
+
+        let wait = match timing {
+            CheckTiming::Now => Duration::seconds(0),
+            CheckTiming::At(at) => at - Utc::now(),
+        };
+
+        if wait > Duration::seconds(0) {
+            let probe = Arc::new((Mutex::new(()), Condvar::new()));
+            let probe2 = probe.clone();
+            thread::spawn(move || {
+                let (_, ref cvar) = *probe2;
+                thread::sleep(wait.to_std().unwrap());
+                cvar.notify_one();
+            });
+
+            let (ref lock, ref cvar) = *probe;
+            let _ = cvar.wait(lock.lock().unwrap());
+        }
 
         debug!("Moving to Probe state.");
         Ok(StateMachine::Probe(self.into()))
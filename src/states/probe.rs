@@ -7,6 +7,7 @@ use super::{
     Download, Idle, Poll, State, StateChangeImpl, StateMachine,
 };
 use crate::client::Api;
+use crate::metrics::{self, Event, UpdateCheckFailureReason};
 
 use slog::{slog_debug, slog_error, slog_info};
 use slog_scope::{debug, error, info};
@@ -34,7 +35,8 @@ impl StateChangeImpl for State<Probe> {
     }
 
     fn handle(self) -> Result<StateMachine, failure::Error> {
-        use crate::client::ProbeResponse;
+        use crate::client::{omaha::OmahaService, ProbeResponse, UpdateService};
+        use crate::settings::UpdateProtocol;
         use chrono::{Duration, Utc};
         use std::thread;
 
@@ -44,16 +46,42 @@ impl StateChangeImpl for State<Probe> {
             .clone()
             .unwrap_or_else(|| shared_state!().settings.network.server_address.clone());
 
+        let service: Box<dyn UpdateService + '_> = match shared_state!().settings.network.protocol {
+            UpdateProtocol::UpdateHub => Box::new(Api::new(&server_address)),
+            UpdateProtocol::Omaha => Box::new(OmahaService::new(&server_address)),
+        };
+
+        metrics::report(Event::UpdateCheckStarted);
+
         let r = loop {
-            let probe = Api::new(&server_address)
-                .probe(&shared_state!().runtime_settings, &shared_state!().firmware);
-            if let Err(e) = probe {
-                error!("{}", e);
-                shared_state_mut!().runtime_settings.inc_retries();
-                thread::sleep(Duration::seconds(1).to_std().unwrap());
-            } else {
-                shared_state_mut!().runtime_settings.clear_retries();
-                break probe?;
+            let probe = service.probe(&shared_state!().runtime_settings, &shared_state!().firmware);
+            match probe {
+                Ok(response) => {
+                    shared_state_mut!().runtime_settings.clear_retries();
+                    metrics::report(Event::UpdateCheckSucceeded);
+                    break response;
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    shared_state_mut!().runtime_settings.inc_retries();
+                    let retries = shared_state!().runtime_settings.retries();
+
+                    if let Some(max_retries) = shared_state!().settings.network.max_retries {
+                        if u64::from(retries) >= max_retries {
+                            error!("Giving up probing after {} consecutive failures.", retries);
+                            shared_state_mut!().runtime_settings.clear_retries();
+                            // This whole check cycle failed, so back off
+                            // the next one too (see `states::poll`).
+                            shared_state_mut!().runtime_settings.inc_consecutive_failed_checks()?;
+                            metrics::report(Event::UpdateCheckFailed {
+                                reason: classify_probe_error(&e),
+                            });
+                            return Ok(StateMachine::Idle(self.into()));
+                        }
+                    }
+
+                    thread::sleep(backoff_delay(&shared_state!().settings.network, retries));
+                }
             }
         };
 
@@ -65,13 +93,20 @@ impl StateChangeImpl for State<Probe> {
         };
 
         match r {
-            ProbeResponse::NoUpdate => {
+            ProbeResponse::NoUpdate(try_again_in) => {
                 debug!("Moving to Idle state as no update is available.");
 
+                set_server_requested_interval(try_again_in)?;
+
                 // Store timestamp of last polling
                 shared_state_mut!()
                     .runtime_settings
                     .set_last_polling(Utc::now())?;
+                // The check cycle completed, so the next one goes back
+                // to the unbacked-off interval.
+                shared_state_mut!()
+                    .runtime_settings
+                    .clear_consecutive_failed_checks()?;
                 Ok(StateMachine::Idle(self.into()))
             }
 
@@ -80,13 +115,27 @@ impl StateChangeImpl for State<Probe> {
                 Ok(StateMachine::Poll(self.into()))
             }
 
-            ProbeResponse::Update(u) => {
+            ProbeResponse::Update(u, rollout, try_again_in) => {
+                set_server_requested_interval(try_again_in)?;
+
                 // Ensure the package is compatible
-                u.compatible_with(&shared_state!().firmware)?;
+                if let Err(e) = u.compatible_with(&shared_state!().firmware) {
+                    metrics::report(Event::UpdateCheckFailed {
+                        reason: UpdateCheckFailureReason::CheckRequirementsFailed {
+                            message: e.to_string(),
+                        },
+                    });
+                    return Err(e);
+                }
                 // Store timestamp of last polling
                 shared_state_mut!()
                     .runtime_settings
                     .set_last_polling(Utc::now())?;
+                // The check cycle completed, so the next one goes back
+                // to the unbacked-off interval.
+                shared_state_mut!()
+                    .runtime_settings
+                    .clear_consecutive_failed_checks()?;
 
                 if Some(u.package_uid()) == shared_state!().runtime_settings.applied_package_uid() {
                     info!(
@@ -94,6 +143,16 @@ impl StateChangeImpl for State<Probe> {
                     );
                     debug!("Moving to Idle state as this update package is already installed.");
                     Ok(StateMachine::Idle(self.into()))
+                } else if let Some(window) = rollout {
+                    if device_is_selected_for_rollout(&u, window)? {
+                        debug!("Moving to Download state to process the update package.");
+                        Ok(StateMachine::Download(State(Download {
+                            update_package: u,
+                        })))
+                    } else {
+                        debug!("Device outside the current staged rollout window. Re-polling later.");
+                        Ok(StateMachine::Poll(self.into()))
+                    }
                 } else {
                     debug!("Moving to Download state to process the update package.");
                     Ok(StateMachine::Download(State(Download {
@@ -105,6 +164,98 @@ impl StateChangeImpl for State<Probe> {
     }
 }
 
+/// Persists (or clears) the server's `try-again-in` hint so
+/// `states::poll::DefaultPollPolicy` prefers it over
+/// `settings.polling.interval` for the next sleep, letting a backend
+/// throttle or accelerate a whole fleet without reconfiguring devices.
+fn set_server_requested_interval(try_again_in: Option<i64>) -> Result<(), failure::Error> {
+    match try_again_in {
+        Some(seconds) => shared_state_mut!()
+            .runtime_settings
+            .set_server_requested_interval(chrono::Duration::seconds(seconds)),
+        None => shared_state_mut!().runtime_settings.clear_server_requested_interval(),
+    }
+}
+
+/// Turns a probe error into a `metrics::UpdateCheckFailureReason`,
+/// picking out the status code from the "Status: NNN" suffix the
+/// client's own `bail!`s use whenever the server answered (as opposed
+/// to the request never reaching it).
+fn classify_probe_error(e: &failure::Error) -> UpdateCheckFailureReason {
+    let message = e.to_string();
+    match message
+        .rsplit("Status: ")
+        .next()
+        .and_then(|s| s.trim().parse::<u16>().ok())
+    {
+        Some(status) => UpdateCheckFailureReason::Server { status },
+        None => UpdateCheckFailureReason::Network { message },
+    }
+}
+
+/// Delay before the next probe retry: `backoff_base * 2^retries`,
+/// capped at `backoff_cap`, plus uniform jitter in `[0, delay/2]` so a
+/// fleet retrying in lock-step doesn't hammer the server together.
+fn backoff_delay(network: &crate::settings::Network, retries: u32) -> std::time::Duration {
+    use rand::Rng;
+
+    let delay = network
+        .backoff_base
+        .to_std()
+        .unwrap()
+        .saturating_mul(2u32.saturating_pow(retries))
+        .min(network.backoff_cap.to_std().unwrap());
+
+    let jitter_bound_ms = (delay.as_millis() / 2) as u64;
+    let jitter_ms = rand::thread_rng().gen_range(0, jitter_bound_ms + 1);
+
+    delay + std::time::Duration::from_millis(jitter_ms)
+}
+
+/// Decides whether this device is part of the current staged rollout
+/// for `u`, based on a stable per-device position derived from
+/// `product_uid`/`package_uid` and the rollout `window`'s current
+/// target fraction. The position is deterministic and monotonic across
+/// probes, so a device never "un-selects" itself mid-rollout.
+fn device_is_selected_for_rollout(
+    u: &crate::update_package::UpdatePackage,
+    window: crate::client::RolloutWindow,
+) -> Result<bool, failure::Error> {
+    use chrono::Utc;
+    use crypto_hash::{hex_digest, Algorithm};
+
+    let package_uid = u.package_uid();
+    let position = {
+        let digest = hex_digest(
+            Algorithm::SHA256,
+            format!("{}{}", shared_state!().firmware.product_uid, package_uid).as_bytes(),
+        );
+        let n = u64::from_str_radix(&digest[..8], 16).unwrap_or(0);
+        f64::from((n % 10_000) as u32) / 10_000.0
+    };
+
+    let now = Utc::now();
+    let first_seen = shared_state!()
+        .runtime_settings
+        .rollout_first_seen_at(&package_uid)
+        .unwrap_or_else(|| {
+            let _ = shared_state_mut!()
+                .runtime_settings
+                .set_rollout_first_seen_at(&package_uid, now);
+            now
+        });
+
+    let current_fraction = match window.window_seconds {
+        Some(window_seconds) if window_seconds > 0 => {
+            let elapsed = (now - first_seen).num_seconds().max(0) as f64;
+            f64::from(window.fraction) * (elapsed / window_seconds as f64).min(1.0)
+        }
+        _ => f64::from(window.fraction),
+    };
+
+    Ok(position <= current_fraction)
+}
+
 #[test]
 fn update_not_available() {
     use super::*;
@@ -272,7 +423,7 @@ fn skip_same_package_uid() {
         )
         .unwrap();
 
-    if let ProbeResponse::Update(u) = probe {
+    if let ProbeResponse::Update(u, _, _) = probe {
         runtime_settings
             .set_applied_package_uid(&u.package_uid())
             .unwrap();
@@ -0,0 +1,61 @@
+// Copyright (C) 2018 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use Result;
+
+use firmware::installation_set;
+use metrics::{self, Event};
+use states::{
+    transition::{state_change_callback, Transition},
+    Idle, State, StateChangeImpl, StateMachine, MAX_BOOT_ATTEMPTS,
+};
+
+#[derive(Debug, PartialEq)]
+pub(super) struct ValidateBoot {}
+
+create_state_step!(ValidateBoot => Idle);
+
+/// If we just rebooted into a freshly installed slot, confirm it
+/// booted correctly before moving on to `State<Idle>`; otherwise leave
+/// it alone and let the bootloader's own fallback handle the rest.
+impl StateChangeImpl for State<ValidateBoot> {
+    fn handle(mut self) -> Result<StateMachine> {
+        let pending = self
+            .runtime_settings
+            .upgrade_to_installation_set()
+            .expect("ValidateBoot reached without a pending installation set");
+
+        info!("booting from a recent installation");
+        if installation_set::active()? == pending {
+            match state_change_callback(&self.settings.firmware.metadata_path, "validation")? {
+                Transition::Cancel => {
+                    warn!("validate callback has failed");
+                    installation_set::swap_active()?;
+                    warn!("swapped active installation set and running rollback");
+                    self.runtime_settings.clear_upgrade_to_installation_set()?;
+                    self.runtime_settings.clear_boot_attempts()?;
+                    metrics::report(Event::RebootOutcome { succeeded: false });
+                    bail!("installation validation failed, rolled back");
+                }
+                Transition::Continue => {
+                    installation_set::validate()?;
+                    self.runtime_settings.clear_upgrade_to_installation_set()?;
+                    self.runtime_settings.clear_boot_attempts()?;
+                    metrics::report(Event::RebootOutcome { succeeded: true });
+                }
+            }
+        } else if self.runtime_settings.boot_attempts() >= MAX_BOOT_ATTEMPTS {
+            warn!(
+                "new installation set failed to boot {} times, giving up",
+                MAX_BOOT_ATTEMPTS
+            );
+            self.runtime_settings.clear_upgrade_to_installation_set()?;
+            self.runtime_settings.clear_boot_attempts()?;
+        } else {
+            self.runtime_settings.inc_boot_attempts()?;
+        }
+
+        Ok(StateMachine::Idle(self.into()))
+    }
+}
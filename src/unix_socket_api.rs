@@ -0,0 +1,112 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Exposes the same control surface as [`http_api`](crate::http_api) —
+//! probe, state, abort download and the recent log buffer — as
+//! line-delimited JSON requests/responses over a Unix domain socket,
+//! so headless/embedded integrators can drive updatehub without
+//! running an HTTP listener.
+//!
+//! This gateway is optional and is only spawned when
+//! `settings.network.unix_socket_gateway` is set; it reuses the exact
+//! `actor::*::Request`/`Response` types the HTTP gateway already
+//! marshals to/from JSON.
+
+use crate::states::actor;
+use actix::Addr;
+use futures::future::Future;
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+};
+
+/// A single line of input on the socket: `{"command": "probe", ...}`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Command {
+    Info,
+    Log,
+    Probe {
+        #[serde(default)]
+        server_address: Option<String>,
+    },
+    DownloadAbort,
+}
+
+/// Binds `path` (removing any stale socket file left over from a
+/// previous run) and serves `Command`s, one connection at a time,
+/// until the process exits. Meant to be spawned on its own thread next
+/// to the HTTP server in `run()`.
+pub fn run(path: &Path, addr: Addr<actor::Machine>) -> std::io::Result<()> {
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if let Err(e) = handle_connection(stream, addr.clone()) {
+                    error!("unix socket gateway: {}", e);
+                }
+            }
+            Err(e) => error!("unix socket gateway: failed to accept connection: {}", e),
+        }
+    }
+
+    Ok(())
+}
+
+fn handle_connection(stream: UnixStream, addr: Addr<actor::Machine>) -> std::io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str::<Command>(&line) {
+            Ok(command) => dispatch(command, &addr),
+            Err(e) => json!({ "error": format!("invalid command: {}", e) }),
+        };
+
+        writeln!(writer, "{}", response)?;
+    }
+
+    Ok(())
+}
+
+fn dispatch(command: Command, addr: &Addr<actor::Machine>) -> serde_json::Value {
+    match command {
+        Command::Info => addr
+            .send(actor::info::Request)
+            .wait()
+            .map(|info| serde_json::to_value(info).unwrap_or_default())
+            .unwrap_or_else(|_| json!({ "error": "internal error" })),
+        Command::Log => json!(crate::logger::buffer()),
+        Command::Probe { server_address } => {
+            match addr.send(actor::probe::Request(server_address)).wait() {
+                Ok(actor::probe::Response::RequestAccepted(state)) => {
+                    json!({ "busy": false, "current-state": state })
+                }
+                Ok(actor::probe::Response::InvalidState(state)) => {
+                    json!({ "busy": true, "current-state": state })
+                }
+                Err(_) => json!({ "error": "internal error" }),
+            }
+        }
+        Command::DownloadAbort => match addr.send(actor::download_abort::Request).wait() {
+            Ok(actor::download_abort::Response::RequestAccepted) => {
+                json!({ "message": "request accepted, download aborted" })
+            }
+            Ok(actor::download_abort::Response::InvalidState) => {
+                json!({ "error": "there is no download to be aborted" })
+            }
+            Err(_) => json!({ "error": "internal error" }),
+        },
+    }
+}
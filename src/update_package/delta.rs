@@ -0,0 +1,95 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Support for delta objects: instead of a full object, the server may
+//! advertise a patch against an object the device already has from a
+//! previous installation, cutting the bytes transferred for small,
+//! incremental changes.
+
+use crypto_hash::{hex_digest, Algorithm};
+use serde::Deserialize;
+use std::path::Path;
+
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct DeltaObject {
+    /// sha256sum of the object already present on the device that the
+    /// patch is applied against.
+    pub(crate) source_sha256sum: String,
+    /// sha256sum the reconstructed object must match.
+    pub(crate) sha256sum: String,
+    pub(crate) size: u64,
+}
+
+impl DeltaObject {
+    /// Reconstructs the target object at `download_dir.join(&self.sha256sum)`
+    /// by applying the bsdiff-style `patch` against the locally present
+    /// source object, verifying the result against `self.sha256sum`
+    /// before leaving it in place.
+    ///
+    /// Returns `false` (without writing anything) if the source object
+    /// is missing, or the patch fails to apply or to verify, so the
+    /// caller can fall back to a full download of the target object.
+    pub(crate) fn reconstruct(&self, download_dir: &Path, patch: &[u8]) -> bool {
+        let source = match std::fs::read(download_dir.join(&self.source_sha256sum)) {
+            Ok(source) => source,
+            Err(_) => return false,
+        };
+
+        let mut target = Vec::new();
+        if bsdiff::patch(&source, &mut std::io::Cursor::new(patch), &mut target).is_err() {
+            return false;
+        }
+
+        if hex_digest(Algorithm::SHA256, &target) != self.sha256sum {
+            return false;
+        }
+
+        std::fs::write(download_dir.join(&self.sha256sum), target).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reconstructs_when_patch_and_source_are_valid() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let source = b"the quick brown fox jumps over the lazy dog";
+        let target = b"the quick brown fox leaps over the lazy dog";
+
+        let mut patch = Vec::new();
+        bsdiff::diff(source, target, &mut patch).unwrap();
+
+        let source_sha256sum = hex_digest(Algorithm::SHA256, source);
+        let target_sha256sum = hex_digest(Algorithm::SHA256, target);
+        std::fs::write(tmpdir.path().join(&source_sha256sum), source).unwrap();
+
+        let delta = DeltaObject {
+            source_sha256sum,
+            sha256sum: target_sha256sum.clone(),
+            size: target.len() as u64,
+        };
+
+        assert!(delta.reconstruct(tmpdir.path(), &patch));
+        assert_eq!(
+            std::fs::read(tmpdir.path().join(&target_sha256sum)).unwrap(),
+            target
+        );
+    }
+
+    #[test]
+    fn fails_without_the_source_object() {
+        let tmpdir = tempfile::tempdir().unwrap();
+
+        let delta = DeltaObject {
+            source_sha256sum: "missing".to_string(),
+            sha256sum: "target".to_string(),
+            size: 0,
+        };
+
+        assert!(!delta.reconstruct(tmpdir.path(), &[]));
+    }
+}
@@ -0,0 +1,169 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Support for the `install-if-different` object metadata: lets an
+//! object declare how to tell whether its target already holds the
+//! content it would install, so the `Install` state can skip writing
+//! (and wearing out) objects that are already up to date.
+
+use crypto_hash::{hex_digest, Algorithm};
+use serde::Deserialize;
+use std::{
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+/// How to decide whether `target` already holds an object's content.
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "kebab-case", tag = "install-if-different")]
+pub(crate) enum InstallIfDifferent {
+    /// Hash the first `size` bytes of the target and compare against
+    /// the object's own `sha256sum`.
+    Sha256sum,
+    /// Read a version string out of the target and compare it to
+    /// `version`. `marker` is a literal that precedes the version
+    /// token (e.g. `"VERSION="`); with no `marker`, the whole target
+    /// is read and trimmed. This is a plain substring search, not a
+    /// regex engine, to avoid a dependency the rest of the crate
+    /// doesn't otherwise need.
+    Pattern {
+        #[serde(default)]
+        marker: Option<String>,
+        version: String,
+    },
+}
+
+impl InstallIfDifferent {
+    /// Returns whether `target` already matches what this object would
+    /// install. Any error reading `target` (most commonly, it not
+    /// existing yet) is treated as "doesn't match" rather than
+    /// propagated, since that just means the object is installed as
+    /// usual.
+    pub(crate) fn matches(&self, target: &Path, size: u64, sha256sum: &str) -> bool {
+        match self {
+            InstallIfDifferent::Sha256sum => Self::matches_sha256sum(target, size, sha256sum),
+            InstallIfDifferent::Pattern { marker, version } => {
+                Self::matches_pattern(target, marker.as_deref(), version)
+            }
+        }
+    }
+
+    fn matches_sha256sum(target: &Path, size: u64, sha256sum: &str) -> bool {
+        let mut file = match File::open(target) {
+            Ok(file) => file,
+            Err(_) => return false,
+        };
+
+        let mut buf = vec![0u8; size as usize];
+        if file.read_exact(&mut buf).is_err() {
+            return false;
+        }
+
+        hex_digest(Algorithm::SHA256, &buf) == sha256sum
+    }
+
+    fn matches_pattern(target: &Path, marker: Option<&str>, version: &str) -> bool {
+        let mut contents = String::new();
+        if File::open(target).and_then(|mut f| f.read_to_string(&mut contents)).is_err() {
+            return false;
+        }
+
+        match marker {
+            Some(marker) => contents
+                .find(marker)
+                .map(|pos| contents[pos + marker.len()..].split_whitespace().next().unwrap_or(""))
+                .map(|found| found == version)
+                .unwrap_or(false),
+            None => contents.trim() == version,
+        }
+    }
+}
+
+/// Pairs an object's install target with the check (if any) that
+/// determines whether its content is already there.
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+pub(crate) struct ObjectCheck {
+    pub(crate) target: PathBuf,
+    pub(crate) size: u64,
+    pub(crate) sha256sum: String,
+    #[serde(flatten)]
+    pub(crate) install_if_different: Option<InstallIfDifferent>,
+}
+
+impl ObjectCheck {
+    /// Whether this object can be skipped because its target already
+    /// holds the content it would install. Objects with no
+    /// `install-if-different` rule are always installed.
+    pub(crate) fn already_installed(&self) -> bool {
+        self.install_if_different
+            .as_ref()
+            .map_or(false, |check| check.matches(&self.target, self.size, &self.sha256sum))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn sha256sum_matches_identical_content() {
+        let tmpfile = NamedTempFile::new().unwrap();
+        std::fs::write(tmpfile.path(), b"the quick brown fox").unwrap();
+        let sha256sum = hex_digest(Algorithm::SHA256, b"the quick brown fox");
+
+        assert!(InstallIfDifferent::Sha256sum.matches(tmpfile.path(), 19, &sha256sum));
+    }
+
+    #[test]
+    fn sha256sum_does_not_match_different_content() {
+        let tmpfile = NamedTempFile::new().unwrap();
+        std::fs::write(tmpfile.path(), b"the quick brown fox").unwrap();
+
+        assert!(!InstallIfDifferent::Sha256sum.matches(tmpfile.path(), 20, "deadbeef"));
+    }
+
+    #[test]
+    fn sha256sum_does_not_match_missing_target() {
+        assert!(!InstallIfDifferent::Sha256sum.matches(Path::new("/does/not/exist"), 4, "deadbeef"));
+    }
+
+    #[test]
+    fn pattern_matches_version_after_marker() {
+        let mut tmpfile = NamedTempFile::new().unwrap();
+        tmpfile.write_all(b"BOARD-REV=3\nVERSION=1.2.3\n").unwrap();
+
+        let install_if_different = InstallIfDifferent::Pattern {
+            marker: Some("VERSION=".to_string()),
+            version: "1.2.3".to_string(),
+        };
+        assert!(install_if_different.matches(tmpfile.path(), 0, ""));
+    }
+
+    #[test]
+    fn pattern_does_not_match_different_version() {
+        let mut tmpfile = NamedTempFile::new().unwrap();
+        tmpfile.write_all(b"VERSION=1.2.3\n").unwrap();
+
+        let install_if_different = InstallIfDifferent::Pattern {
+            marker: Some("VERSION=".to_string()),
+            version: "9.9.9".to_string(),
+        };
+        assert!(!install_if_different.matches(tmpfile.path(), 0, ""));
+    }
+
+    #[test]
+    fn object_check_without_a_rule_is_never_skipped() {
+        let check = ObjectCheck {
+            target: PathBuf::from("/does/not/matter"),
+            size: 0,
+            sha256sum: String::new(),
+            install_if_different: None,
+        };
+
+        assert!(!check.already_installed());
+    }
+}
@@ -0,0 +1,48 @@
+// Copyright (C) 2019 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use serde::Deserialize;
+
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Filesystem {
+    Ext2,
+    Ext3,
+    Ext4,
+    Ubifs,
+    /// FAT12/16/32, picked by partition size at format time. Formatted
+    /// and written to in-process via the pure-Rust `fatfs` crate
+    /// rather than `mkfs.vfat`/a kernel mount, so it works on minimal
+    /// images that don't ship either.
+    #[serde(rename = "vfat")]
+    Fat,
+}
+
+impl Filesystem {
+    /// The `mkfs.<suffix>`/`mount -t <suffix>` name for this
+    /// filesystem. `Fat` has no entry here: it's never shelled out to
+    /// or kernel-mounted, see `utils::fs`.
+    pub(crate) fn as_mkfs_suffix(self) -> &'static str {
+        match self {
+            Filesystem::Ext2 => "ext2",
+            Filesystem::Ext3 => "ext3",
+            Filesystem::Ext4 => "ext4",
+            Filesystem::Ubifs => "ubifs",
+            Filesystem::Fat => unreachable!("FAT is never formatted/mounted through mkfs/mount"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    fn deserialize() {
+        assert_eq!(Filesystem::Ext4, serde_json::from_value(json!("ext4")).unwrap());
+        assert_eq!(Filesystem::Fat, serde_json::from_value(json!("vfat")).unwrap());
+    }
+}
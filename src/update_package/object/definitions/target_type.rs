@@ -12,6 +12,32 @@ pub enum TargetType {
     MTDName(String),
 }
 
+impl TargetType {
+    /// The concrete name/path this object installs to, with any
+    /// `$UH_INSTALLATION_SET` placeholder substituted for the slot the
+    /// `install` state picked for this update (set as an environment
+    /// variable before objects are installed). This lets a single
+    /// A/B-aware package address either slot through one `target`
+    /// string, e.g. `/dev/mmcblk0p$UH_INSTALLATION_SET`, instead of
+    /// shipping two near-identical objects.
+    pub(crate) fn get_target(&self) -> Result<String, failure::Error> {
+        let target = match self {
+            TargetType::Device(target)
+            | TargetType::UBIVolume(target)
+            | TargetType::MTDName(target) => target,
+        };
+
+        Ok(match std::env::var("UH_INSTALLATION_SET") {
+            Ok(installation_set) => target.replace("$UH_INSTALLATION_SET", &installation_set),
+            Err(_) => target.clone(),
+        })
+    }
+
+    pub(crate) fn valid(&self) -> Result<(), failure::Error> {
+        self.get_target().map(|_| ())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
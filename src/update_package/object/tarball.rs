@@ -7,7 +7,7 @@ use crate::utils;
 use serde::Deserialize;
 use slog::slog_info;
 use slog_scope::info;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Deserialize, PartialEq, Debug)]
 #[serde(rename_all = "kebab-case")]
@@ -32,6 +32,33 @@ pub(crate) struct Tarball {
 
 impl_object_type!(Tarball);
 
+impl Tarball {
+    /// Checks that `path`'s filesystem has room for
+    /// `required_uncompressed_size`. When we're about to format the
+    /// target, the uncompressed tree only needs to fit the partition's
+    /// full capacity; otherwise it has to fit whatever's free on the
+    /// filesystem that's already there.
+    fn check_required_space(&self, path: &Path) -> Result<(), failure::Error> {
+        let stat = nix::sys::statvfs::statvfs(path)?;
+        let available = if self.target_format.should_format {
+            stat.blocks() * stat.fragment_size()
+        } else {
+            stat.blocks_available() * stat.fragment_size()
+        };
+
+        if self.required_uncompressed_size > available {
+            bail!(
+                "not enough space on target '{}': {} bytes required, {} available",
+                self.target.get_target()?,
+                self.required_uncompressed_size,
+                available
+            );
+        }
+
+        Ok(())
+    }
+}
+
 impl ObjectInstaller for Tarball {
     fn check_requirements(&self) -> Result<(), failure::Error> {
         info!("'tarball' handle checking requirements");
@@ -39,8 +66,23 @@ impl ObjectInstaller for Tarball {
         match self.target {
             definitions::TargetType::Device(_)
             | definitions::TargetType::UBIVolume(_)
-            | definitions::TargetType::MTDName(_) => self.target.valid().map(|_| ()),
+            | definitions::TargetType::MTDName(_) => self.target.valid()?,
+        }
+
+        // When the target is about to be formatted, mounting it here
+        // would either fail (it may not have a filesystem yet) or
+        // report the soon-to-be-destroyed filesystem's free space
+        // instead of the capacity it'll have once formatted; `install`
+        // re-runs this same check against the freshly formatted
+        // filesystem before writing, which is the right place for it.
+        if self.target_format.should_format {
+            return Ok(());
         }
+
+        let device = self.target.get_target()?;
+        utils::fs::mount_map(&device, self.filesystem, &self.mount_options, |path| {
+            self.check_required_space(path)
+        })
     }
 
     fn install(&self, download_dir: PathBuf) -> Result<(), failure::Error> {
@@ -52,15 +94,13 @@ impl ObjectInstaller for Tarball {
         let format_options = &self.target_format.format_options;
         let source = download_dir.join(self.sha256sum());
 
-        // FIXME: use required_uncompressed_size
-        // if we will format, we check the full size
-        // else we check the remaning size
-
         if self.target_format.should_format {
             utils::fs::format(&device, filesystem, format_options)?;
         }
 
         utils::fs::mount_map(&device, filesystem, mount_options, |path| {
+            self.check_required_space(path)?;
+
             let dest = path.join(&self.target_path.strip_prefix("/")?);
 
             compress_tools::uncompress(
@@ -94,7 +134,10 @@ mod tests {
         static ref SERIALIZE: Arc<Mutex<()>> = Arc::new(Mutex::default());
     }
 
-    fn exec_test_with_tarball<F>(mut f: F) -> Result<(), failure::Error>
+    fn exec_test_with_tarball<F>(
+        filesystem: definitions::Filesystem,
+        mut f: F,
+    ) -> Result<(), failure::Error>
     where
         F: FnMut(&mut Tarball),
     {
@@ -115,12 +158,12 @@ mod tests {
         };
 
         // Format the faked device
-        utils::fs::format(&device, definitions::Filesystem::Ext4, &None)?;
+        utils::fs::format(&device, filesystem, &None)?;
 
         // Generate base copy object
         let mut obj = Tarball {
             filename: "".to_string(),
-            filesystem: definitions::Filesystem::Ext4,
+            filesystem,
             size: CONTENT_SIZE as u64,
             sha256sum: "tree.tar".to_string(),
             target: definitions::TargetType::Device(device.clone()),
@@ -134,7 +177,7 @@ mod tests {
         f(&mut obj);
 
         // Setup preinstall structure
-        utils::fs::mount_map(&device, definitions::Filesystem::Ext4, &"", |path| {
+        utils::fs::mount_map(&device, filesystem, &"", |path| {
             fs::create_dir(path.join("existing_dir"))?;
             Ok(())
         })?;
@@ -150,11 +193,15 @@ mod tests {
             obj.filesystem,
             &obj.mount_options.clone(),
             |path| {
+                // FAT has no uid/gid/mode bits to preserve, so only
+                // the tree's structure and content are checked there.
                 let assert_metadata = |p: &Path| -> Result<(), failure::Error> {
-                    let metadata = p.metadata()?;
-                    assert_eq!(metadata.mode() % 0o1000, 0o664);
-                    assert_eq!(metadata.uid(), 1000);
-                    assert_eq!(metadata.gid(), 1000);
+                    if filesystem != definitions::Filesystem::Fat {
+                        let metadata = p.metadata()?;
+                        assert_eq!(metadata.mode() % 0o1000, 0o664);
+                        assert_eq!(metadata.uid(), 1000);
+                        assert_eq!(metadata.gid(), 1000);
+                    }
 
                     Ok(())
                 };
@@ -174,13 +221,35 @@ mod tests {
     #[test]
     #[ignore]
     fn install_over_formated_partion() {
-        exec_test_with_tarball(|obj| obj.target_format.should_format = true).unwrap();
+        exec_test_with_tarball(definitions::Filesystem::Ext4, |obj| {
+            obj.target_format.should_format = true
+        })
+        .unwrap();
     }
 
     #[test]
     #[ignore]
     fn install_over_unformated_partion() {
-        exec_test_with_tarball(|obj| obj.target_path = PathBuf::from("/existing_dir")).unwrap();
+        exec_test_with_tarball(definitions::Filesystem::Ext4, |obj| {
+            obj.target_path = PathBuf::from("/existing_dir")
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn install_rejected_when_required_space_exceeds_target() {
+        let result = exec_test_with_tarball(definitions::Filesystem::Ext4, |obj| {
+            obj.required_uncompressed_size = u64::max_value();
+        });
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    #[ignore]
+    fn install_over_vfat_partition() {
+        exec_test_with_tarball(definitions::Filesystem::Fat, |_| {}).unwrap();
     }
 
     #[test]
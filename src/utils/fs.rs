@@ -0,0 +1,158 @@
+// Copyright (C) 2019 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Filesystem helpers shared by the object installers: formatting a
+//! target device/volume and mounting it so a closure can write files
+//! into it, without each installer having to know the mechanics of
+//! any particular filesystem.
+
+use crate::update_package::object::definitions::Filesystem;
+use std::{
+    fs::OpenOptions,
+    io,
+    path::Path,
+    process::Command,
+};
+
+/// Formats `target` as `filesystem`, passing `format_options` through
+/// to the underlying tool verbatim (e.g. `-L somelabel`).
+///
+/// `Filesystem::Fat` is formatted in-process with the pure-Rust
+/// `fatfs` crate instead of shelling out to `mkfs.vfat`, so targets
+/// can be deployed to an EFI System Partition on images that don't
+/// ship it; every other filesystem still goes through its usual
+/// `mkfs.<fs>` binary.
+pub(crate) fn format(
+    target: &str,
+    filesystem: Filesystem,
+    format_options: &Option<String>,
+) -> Result<(), failure::Error> {
+    if let Filesystem::Fat = filesystem {
+        return format_fat(target);
+    }
+
+    let mut cmd = Command::new(format!("mkfs.{}", filesystem.as_mkfs_suffix()));
+    cmd.arg(target);
+    if let Some(options) = format_options {
+        cmd.args(options.split_whitespace());
+    }
+
+    let status = cmd.status()?;
+    if !status.success() {
+        bail!("'{}' failed on '{}' with {}", cmd.get_program().to_string_lossy(), target, status);
+    }
+
+    Ok(())
+}
+
+fn format_fat(target: &str) -> Result<(), failure::Error> {
+    let file = OpenOptions::new().read(true).write(true).open(target)?;
+    let len = file.metadata()?.len();
+
+    // Same size thresholds `mkfs.vfat` itself uses to pick a FAT
+    // variant: FAT12/16 can't address a large enough cluster count
+    // for bigger volumes.
+    let fat_type = if len < 4 * 1024 * 1024 {
+        fatfs::FatType::Fat12
+    } else if len < 512 * 1024 * 1024 {
+        fatfs::FatType::Fat16
+    } else {
+        fatfs::FatType::Fat32
+    };
+
+    fatfs::format_volume(&file, fatfs::FormatVolumeOptions::new().fat_type(fat_type))?;
+
+    Ok(())
+}
+
+/// Mounts `target` (already formatted as `filesystem`) and hands the
+/// mount path to `f`, unmounting afterward regardless of whether `f`
+/// succeeds.
+///
+/// `Filesystem::Fat` targets aren't mounted through the kernel: `f`
+/// instead receives a scratch directory, and whatever it wrote there
+/// is copied into the FAT volume (via `fatfs`) once it returns. FAT
+/// has no uid/gid to preserve, only the directory structure and file
+/// contents carry over.
+pub(crate) fn mount_map<F>(
+    target: &str,
+    filesystem: Filesystem,
+    mount_options: &str,
+    f: F,
+) -> Result<(), failure::Error>
+where
+    F: FnOnce(&Path) -> Result<(), failure::Error>,
+{
+    if let Filesystem::Fat = filesystem {
+        return mount_map_fat(target, f);
+    }
+
+    let mountpoint = tempfile::tempdir()?;
+
+    nix::mount::mount(
+        Some(target),
+        mountpoint.path(),
+        Some(filesystem.as_mkfs_suffix()),
+        nix::mount::MsFlags::empty(),
+        Some(mount_options),
+    )?;
+
+    let result = f(mountpoint.path());
+
+    nix::mount::umount(mountpoint.path())?;
+
+    result
+}
+
+fn mount_map_fat<F>(target: &str, f: F) -> Result<(), failure::Error>
+where
+    F: FnOnce(&Path) -> Result<(), failure::Error>,
+{
+    let scratch = tempfile::tempdir()?;
+    f(scratch.path())?;
+
+    let file = OpenOptions::new().read(true).write(true).open(target)?;
+    let volume = fatfs::FileSystem::new(file, fatfs::FsOptions::new())?;
+    copy_into_fat_dir(scratch.path(), &volume.root_dir())?;
+
+    Ok(())
+}
+
+fn copy_into_fat_dir(
+    src: &Path,
+    dir: &fatfs::Dir<impl fatfs::ReadWriteSeek>,
+) -> Result<(), failure::Error> {
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+
+        if entry.file_type()?.is_dir() {
+            copy_into_fat_dir(&entry.path(), &dir.create_dir(&name)?)?;
+        } else {
+            let mut dest = dir.create_file(&name)?;
+            io::copy(&mut std::fs::File::open(entry.path())?, &mut dest)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Picks the archive format `compress_tools` should use to extract
+/// `source`, from its file extension.
+pub(crate) fn find_compress_tarball_kind(source: &Path) -> Result<compress_tools::Kind, failure::Error> {
+    let name = source.to_string_lossy();
+
+    Ok(if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        compress_tools::Kind::TarGzip
+    } else if name.ends_with(".tar.bz2") {
+        compress_tools::Kind::TarBzip2
+    } else if name.ends_with(".tar.xz") {
+        compress_tools::Kind::TarXz
+    } else if name.ends_with(".tar") {
+        compress_tools::Kind::Tar
+    } else {
+        bail!("unable to determine archive kind for '{}'", source.display());
+    })
+}
@@ -0,0 +1,102 @@
+// Copyright (C) 2019 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Pushes state machine and log events to subscribed clients over a
+//! WebSocket, so a UI can render live progress without polling the
+//! `/state` and `/log` HTTP endpoints (see `http_api`).
+//!
+//! `actor::Machine` broadcasts an `Event` to every subscribed
+//! `Session` whenever it moves to a new state, reports download
+//! progress, or buffers a new log `Entry`; each `Session` forwards
+//! the events it receives straight to its WebSocket client.
+
+use crate::states::actor;
+use actix::{Actor, Addr, AsyncContext, Handler, Message, Recipient, StreamHandler};
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use serde::Serialize;
+
+pub fn configure(cfg: &mut web::RouterConfig, addr: Addr<actor::Machine>) {
+    cfg.data(addr).route("/ws", web::get().to(events));
+}
+
+fn events(
+    req: HttpRequest,
+    stream: web::Payload,
+    addr: web::Data<Addr<actor::Machine>>,
+) -> Result<HttpResponse, Error> {
+    ws::start(Session { machine: addr.get_ref().clone() }, &req, stream)
+}
+
+/// Event pushed to subscribed WebSocket clients, mirroring the data
+/// already surfaced by the `/state` and `/log` HTTP endpoints.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Event {
+    /// A `move_to_next_state` transition just happened.
+    StateChanged {
+        previous_state: String,
+        current_state: String,
+    },
+    /// Byte-level progress of an in-flight object download.
+    DownloadProgress(crate::client::DownloadProgress),
+    /// A new line was appended to the update log buffer.
+    LogEntry(crate::logger::Entry),
+}
+
+impl Message for Event {
+    type Result = ();
+}
+
+/// Registers a session's `Recipient<Event>` with the machine, so it
+/// starts receiving every broadcast event.
+pub(crate) struct Subscribe(pub(crate) Recipient<Event>);
+
+impl Message for Subscribe {
+    type Result = ();
+}
+
+/// Unregisters a session's `Recipient<Event>`, stopping delivery once
+/// its WebSocket connection closes.
+pub(crate) struct Unsubscribe(pub(crate) Recipient<Event>);
+
+impl Message for Unsubscribe {
+    type Result = ();
+}
+
+struct Session {
+    machine: Addr<actor::Machine>,
+}
+
+impl Actor for Session {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.machine.do_send(Subscribe(ctx.address().recipient()));
+    }
+
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        self.machine.do_send(Unsubscribe(ctx.address().recipient()));
+    }
+}
+
+impl StreamHandler<ws::Message, ws::ProtocolError> for Session {
+    fn handle(&mut self, msg: ws::Message, ctx: &mut Self::Context) {
+        // The stream only pushes events to the client; the one thing
+        // we need to answer is keep-alive pings from the peer.
+        if let ws::Message::Ping(msg) = msg {
+            ctx.pong(&msg);
+        }
+    }
+}
+
+impl Handler<Event> for Session {
+    type Result = ();
+
+    fn handle(&mut self, event: Event, ctx: &mut Self::Context) {
+        if let Ok(payload) = serde_json::to_string(&event) {
+            ctx.text(payload);
+        }
+    }
+}
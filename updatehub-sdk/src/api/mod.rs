@@ -69,6 +69,29 @@ pub mod abort_download {
     }
 }
 
+pub mod events {
+    use super::state;
+    use serde::{Deserialize, Serialize};
+
+    /// An event pushed by the agent's `/events` Server-Sent-Events
+    /// endpoint as it moves through the state machine, consumed by
+    /// `Client::subscribe` instead of polling `/log`.
+    #[derive(Clone, Debug, Deserialize, Serialize)]
+    #[serde(tag = "type", rename_all = "kebab-case")]
+    pub enum StateMachineEvent {
+        /// The agent entered or left a state, reusing the same shape
+        /// already returned by `/probe`, `/local_install` and friends.
+        StateChanged(state::Response),
+        /// Byte-level progress of the object currently being
+        /// downloaded.
+        DownloadProgress { current: u64, total: u64 },
+        /// The update finished installing successfully.
+        Success,
+        /// The update failed; `message` carries the failure reason.
+        Error { message: String },
+    }
+}
+
 pub mod log {
     use serde::{Deserialize, Serialize};
     use std::collections::HashMap;
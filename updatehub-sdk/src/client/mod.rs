@@ -3,17 +3,47 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use crate::{api, Error, Result};
-use attohttpc::{get, post};
-use std::path::Path;
+use attohttpc::{get, post, RequestBuilder};
+use std::{
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Credentials used to authenticate requests against the agent's HTTP
+/// API. `None` leaves requests unauthenticated.
+#[derive(Clone)]
+pub enum Auth {
+    None,
+    Token(String),
+    ClientCredentials { client_id: String, client_secret: String, token_url: String },
+}
+
+impl Default for Auth {
+    fn default() -> Self {
+        Auth::None
+    }
+}
+
+struct CachedToken {
+    access_token: String,
+    expires_at: Instant,
+}
 
 #[derive(Clone)]
 pub struct Client {
     server_address: String,
+    auth: Auth,
+    token_cache: std::sync::Arc<Mutex<Option<CachedToken>>>,
 }
 
 impl Default for Client {
     fn default() -> Self {
-        Client { server_address: "http://localhost:8080".to_string() }
+        Client {
+            server_address: "http://localhost:8080".to_string(),
+            auth: Auth::None,
+            token_cache: Default::default(),
+        }
     }
 }
 
@@ -22,21 +52,88 @@ impl Client {
         Client { server_address: format!("http://{}", server_address), ..Self::default() }
     }
 
+    /// Same as `new`, but every request carries the given credentials
+    /// in an `Authorization: Bearer <token>` header, refreshing the
+    /// token whenever the agent answers with `401 Unauthorized`.
+    pub fn with_auth(server_address: &str, auth: Auth) -> Self {
+        Client { auth, ..Self::new(server_address) }
+    }
+
+    fn access_token(&self) -> Result<Option<String>> {
+        match &self.auth {
+            Auth::None => Ok(None),
+            Auth::Token(token) => Ok(Some(token.clone())),
+            Auth::ClientCredentials { client_id, client_secret, token_url } => {
+                {
+                    let cache = self.token_cache.lock().unwrap();
+                    if let Some(cached) = cache.as_ref() {
+                        if cached.expires_at > Instant::now() {
+                            return Ok(Some(cached.access_token.clone()));
+                        }
+                    }
+                }
+
+                #[derive(serde::Deserialize)]
+                struct TokenResponse {
+                    access_token: String,
+                    #[serde(default = "default_expires_in")]
+                    expires_in: u64,
+                }
+                fn default_expires_in() -> u64 {
+                    3600
+                }
+
+                let response: TokenResponse = post(token_url)
+                    .form(&[
+                        ("grant_type", "client_credentials"),
+                        ("client_id", client_id.as_str()),
+                        ("client_secret", client_secret.as_str()),
+                    ])?
+                    .send()?
+                    .json()?;
+
+                let access_token = response.access_token;
+                *self.token_cache.lock().unwrap() = Some(CachedToken {
+                    access_token: access_token.clone(),
+                    expires_at: Instant::now() + Duration::from_secs(response.expires_in),
+                });
+
+                Ok(Some(access_token))
+            }
+        }
+    }
+
+    /// Invalidates the cached access token, so the next request forces
+    /// a fresh client-credentials grant.
+    fn invalidate_token(&self) {
+        *self.token_cache.lock().unwrap() = None;
+    }
+
+    fn authenticate(&self, mut request: RequestBuilder) -> Result<RequestBuilder> {
+        if let Some(access_token) = self.access_token()? {
+            request = request.bearer_auth(access_token);
+        }
+        Ok(request)
+    }
+
     pub async fn info(&self) -> Result<api::info::Response> {
-        let response = get(&format!("{}/info", self.server_address)).send()?;
+        let response = self.authenticate(get(&format!("{}/info", self.server_address)))?.send()?;
 
         match response.status() {
             attohttpc::StatusCode::OK => Ok(response.json()?),
+            attohttpc::StatusCode::UNAUTHORIZED => {
+                self.invalidate_token();
+                Err(Error::Unauthorized)
+            }
             _ => Err(Error::UnexpectedResponse(response)),
         }
     }
 
     pub async fn probe(&self, custom: Option<String>) -> Result<api::probe::Response> {
+        let request = self.authenticate(post(&format!("{}/probe", self.server_address)))?;
         let response = match custom {
-            Some(custom_server) => post(&format!("{}/probe", self.server_address))
-                .json(&api::probe::Request { custom_server })?
-                .send()?,
-            None => post(&format!("{}/probe", self.server_address)).send()?,
+            Some(custom_server) => request.json(&api::probe::Request { custom_server })?.send()?,
+            None => request.send()?,
         };
 
         match response.status() {
@@ -44,12 +141,17 @@ impl Client {
             attohttpc::StatusCode::ACCEPTED => {
                 Err(Error::AgentIsBusy(response.json::<api::state::Response>()?))
             }
+            attohttpc::StatusCode::UNAUTHORIZED => {
+                self.invalidate_token();
+                Err(Error::Unauthorized)
+            }
             _ => Err(Error::UnexpectedResponse(response)),
         }
     }
 
     pub async fn local_install(&self, file: &Path) -> Result<api::state::Response> {
-        let response = post(&format!("{}/local_install", self.server_address))
+        let response = self
+            .authenticate(post(&format!("{}/local_install", self.server_address)))?
             .header(attohttpc::header::CONTENT_TYPE, "text/plain")
             .text(format!("{}", file.display()))
             .send()?;
@@ -59,12 +161,17 @@ impl Client {
             attohttpc::StatusCode::UNPROCESSABLE_ENTITY => {
                 Err(Error::AgentIsBusy(response.json::<api::state::Response>()?))
             }
+            attohttpc::StatusCode::UNAUTHORIZED => {
+                self.invalidate_token();
+                Err(Error::Unauthorized)
+            }
             _ => Err(Error::UnexpectedResponse(response)),
         }
     }
 
     pub async fn remote_install(&self, url: String) -> Result<api::state::Response> {
-        let response = post(&format!("{}/remote_install", self.server_address))
+        let response = self
+            .authenticate(post(&format!("{}/remote_install", self.server_address)))?
             .header(attohttpc::header::CONTENT_TYPE, "text/plain")
             .text(url)
             .send()?;
@@ -74,28 +181,89 @@ impl Client {
             attohttpc::StatusCode::UNPROCESSABLE_ENTITY => {
                 Err(Error::AgentIsBusy(response.json::<api::state::Response>()?))
             }
+            attohttpc::StatusCode::UNAUTHORIZED => {
+                self.invalidate_token();
+                Err(Error::Unauthorized)
+            }
             _ => Err(Error::UnexpectedResponse(response)),
         }
     }
 
     pub async fn abort_download(&self) -> Result<api::abort_download::Response> {
-        let response = post(&format!("{}/update/download/abort", self.server_address)).send()?;
+        let response = self
+            .authenticate(post(&format!("{}/update/download/abort", self.server_address)))?
+            .send()?;
 
         match response.status() {
             attohttpc::StatusCode::OK => Ok(response.json()?),
             attohttpc::StatusCode::BAD_REQUEST => {
                 Err(Error::AbortDownloadRefused(response.json::<api::abort_download::Refused>()?))
             }
+            attohttpc::StatusCode::UNAUTHORIZED => {
+                self.invalidate_token();
+                Err(Error::Unauthorized)
+            }
             _ => Err(Error::UnexpectedResponse(response)),
         }
     }
 
     pub async fn log(&self) -> Result<Vec<api::log::Entry>> {
-        let response = get(&format!("{}/log", self.server_address)).send()?;
+        let response = self.authenticate(get(&format!("{}/log", self.server_address)))?.send()?;
 
         match response.status() {
             attohttpc::StatusCode::OK => Ok(response.json()?),
+            attohttpc::StatusCode::UNAUTHORIZED => {
+                self.invalidate_token();
+                Err(Error::Unauthorized)
+            }
             _ => Err(Error::UnexpectedResponse(response)),
         }
     }
+
+    /// Subscribes to the agent's `/events` Server-Sent-Events endpoint
+    /// and returns a `Stream` of `StateMachineEvent`s, so callers can
+    /// render live progress and react to errors immediately instead of
+    /// polling `log`.
+    ///
+    /// The connection is read on a dedicated thread (attohttpc is
+    /// blocking) and each `data: <json>` frame is forwarded, decoded,
+    /// through the returned stream until the connection closes.
+    pub fn subscribe(&self) -> Result<impl futures::Stream<Item = api::events::StateMachineEvent>> {
+        use std::io::{BufRead, BufReader};
+
+        let response =
+            self.authenticate(get(&format!("{}/events", self.server_address)))?.send()?;
+
+        if response.status() == attohttpc::StatusCode::UNAUTHORIZED {
+            self.invalidate_token();
+            return Err(Error::Unauthorized);
+        }
+        if !response.status().is_success() {
+            return Err(Error::UnexpectedResponse(response));
+        }
+
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        std::thread::spawn(move || {
+            let reader = BufReader::new(response);
+            for line in reader.lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+                let data = match line.strip_prefix("data: ") {
+                    Some(data) => data,
+                    None => continue,
+                };
+                let event = match serde_json::from_str(data) {
+                    Ok(event) => event,
+                    Err(_) => continue,
+                };
+                if sender.unbounded_send(event).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(receiver)
+    }
 }
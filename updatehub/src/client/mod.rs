@@ -8,9 +8,15 @@ use crate::{
     update_package::{Signature, UpdatePackage},
 };
 use attohttpc::{
-    header::{HeaderName, CONTENT_TYPE, RANGE, USER_AGENT},
+    header::{HeaderName, AUTHORIZATION, CONTENT_TYPE, RANGE, USER_AGENT},
     StatusCode,
 };
+use openssl::{
+    hash::{Hasher, MessageDigest},
+    pkey::PKey,
+    sign::Verifier,
+};
+use rand::Rng;
 use sdk::api::info::firmware as api;
 use serde::Serialize;
 use slog_scope::debug;
@@ -26,6 +32,31 @@ pub(crate) mod tests;
 
 pub(crate) struct Api<'a> {
     server: &'a str,
+    cup2_key: Option<(&'a str, &'a [u8])>,
+    auth: Auth,
+    retry: RetryPolicy,
+    system_info: Option<&'a crate::system_info::Collector>,
+}
+
+/// Truncated binary exponential backoff parameters for retrying a
+/// failed cloud request, built from `settings.network`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RetryPolicy {
+    base: chrono::Duration,
+    cap: chrono::Duration,
+    max_retries: Option<u64>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self { base: chrono::Duration::seconds(1), cap: chrono::Duration::minutes(1), max_retries: None }
+    }
+}
+
+impl From<&crate::settings::Network> for RetryPolicy {
+    fn from(network: &crate::settings::Network) -> Self {
+        Self { base: network.backoff_base, cap: network.backoff_cap, max_retries: network.max_retries }
+    }
 }
 
 #[derive(Debug)]
@@ -35,6 +66,67 @@ pub(crate) enum ProbeResponse {
     ExtraPoll(i64),
 }
 
+/// Outbound authentication against the cloud server, built from
+/// `settings.network.auth`. `Credentials` performs an OAuth2
+/// client-credentials grant against `token_url` and caches the
+/// resulting access token until it expires; `Token` sends a static
+/// bearer token as-is.
+#[derive(Debug)]
+pub(crate) enum Auth {
+    None,
+    Token(String),
+    Credentials { client_id: String, client_secret: String, token_url: String },
+}
+
+impl From<&crate::settings::Auth> for Auth {
+    fn from(auth: &crate::settings::Auth) -> Self {
+        match (&auth.client_id, &auth.client_secret, &auth.token_url) {
+            (Some(client_id), Some(client_secret), Some(token_url)) => Auth::Credentials {
+                client_id: client_id.clone(),
+                client_secret: client_secret.clone(),
+                token_url: token_url.clone(),
+            },
+            _ => match &auth.token {
+                Some(token) => Auth::Token(token.clone()),
+                None => Auth::None,
+            },
+        }
+    }
+}
+
+/// The outcome of installing (or validating) a single object.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ResultCode {
+    Ok,
+    InstallFailed,
+    DownloadFailed,
+    ValidationFailed,
+    DependencyFailure,
+    GeneralError,
+}
+
+/// Per-object outcome reported alongside a package's overall state, so
+/// the server can tell which object in a multi-object package failed
+/// and why instead of seeing one opaque package-wide error.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct OperationResult {
+    pub(crate) object_uid: String,
+    pub(crate) result_code: ResultCode,
+    pub(crate) output: String,
+}
+
+/// A cached OAuth2 access token, reused until it expires.
+struct CachedToken {
+    access_token: String,
+    expires_at: std::time::Instant,
+}
+
+lazy_static::lazy_static! {
+    static ref TOKEN_CACHE: std::sync::Mutex<Option<CachedToken>> = std::sync::Mutex::new(None);
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug, Error)]
@@ -55,6 +147,54 @@ pub enum Error {
     InvalidHeader(#[from] attohttpc::header::InvalidHeaderValue),
     #[error("Non str header error: {0}")]
     NonStrHeader(#[from] attohttpc::header::ToStrError),
+
+    #[error("response signature verification failed")]
+    SignatureVerificationFailed,
+
+    #[error("OpenSSL error: {0}")]
+    OpenSsl(#[from] openssl::error::ErrorStack),
+
+    #[error("JSON error: {0}")]
+    SerdeJson(#[from] serde_json::Error),
+
+    #[error("downloaded object doesn't match its expected checksum: expected {expected}, got {got}")]
+    ChecksumMismatch { expected: String, got: String },
+}
+
+/// Verifies a CUP2-style response: recomputes
+/// `SHA256(SHA256(request_body) || response_body)` and checks it
+/// against the DER ECDSA signature the server returned in the
+/// `X-Cup-Server-Proof` header (base64-encoded), using the pinned
+/// P-256 public key for the request's key id.
+fn verify_cup2_response(
+    public_key_der: &[u8],
+    request_body: &[u8],
+    response_body: &[u8],
+    proof_header: &[u8],
+) -> Result<()> {
+    let mut request_hasher = Hasher::new(MessageDigest::sha256())?;
+    request_hasher.update(request_body)?;
+    let request_digest = request_hasher.finish()?;
+
+    let mut hasher = Hasher::new(MessageDigest::sha256())?;
+    hasher.update(&request_digest)?;
+    hasher.update(response_body)?;
+    let hash = hasher.finish()?;
+
+    let signature = openssl::base64::decode_block(
+        std::str::from_utf8(proof_header).map_err(|_| Error::SignatureVerificationFailed)?,
+    )
+    .map_err(|_| Error::SignatureVerificationFailed)?;
+
+    let public_key = PKey::public_key_from_der(public_key_der)?;
+    let mut verifier = Verifier::new(MessageDigest::sha256(), &public_key)?;
+    verifier.update(&hash)?;
+
+    if verifier.verify(&signature)? {
+        Ok(())
+    } else {
+        Err(Error::SignatureVerificationFailed)
+    }
 }
 
 // We redefine the metadata structure here because the cloud
@@ -94,6 +234,53 @@ fn post(url: &str) -> attohttpc::RequestBuilder {
         )
 }
 
+/// Parses the starting offset out of a `206 Partial Content`
+/// response's `Content-Range` header (`bytes <start>-<end>/<total>`),
+/// so a resumed download can be checked against the offset it was
+/// actually resumed from before any bytes are appended to disk.
+fn content_range_start(response: &attohttpc::Response) -> Result<u64> {
+    response
+        .headers()
+        .get("content-range")
+        .ok_or(Error::InvalidStatusResponse(response.status()))?
+        .to_str()?
+        .trim_start_matches("bytes ")
+        .split('-')
+        .next()
+        .and_then(|start| start.parse().ok())
+        .ok_or(Error::InvalidStatusResponse(response.status()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Forwards every write to `inner`, feeding the same bytes into
+/// `hasher` and reporting the running total to `on_write` so a
+/// response body can be written to disk, hashed, and have its
+/// progress observed in a single streaming pass instead of buffering
+/// it in memory first.
+struct HashingWriter<'a, 'b, W> {
+    inner: W,
+    hasher: &'a mut Hasher,
+    on_write: &'b mut dyn FnMut(u64),
+}
+
+impl<'a, 'b, W: std::io::Write> std::io::Write for HashingWriter<'a, 'b, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher
+            .update(&buf[..written])
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        (self.on_write)(written as u64);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 fn get(url: &str) -> attohttpc::RequestBuilder {
     attohttpc::RequestBuilder::new(attohttpc::Method::GET, url)
         .connect_timeout(Duration::from_secs(10))
@@ -108,18 +295,211 @@ fn get(url: &str) -> attohttpc::RequestBuilder {
 
 impl<'a> Api<'a> {
     pub(crate) fn new(server: &'a str) -> Self {
-        Self { server }
+        Self {
+            server,
+            cup2_key: None,
+            auth: Auth::None,
+            retry: RetryPolicy::default(),
+            system_info: None,
+        }
+    }
+
+    /// Pins a CUPv2-style P-256 ECDSA public key (and its key id), so
+    /// `probe` verifies every response against it before trusting it.
+    /// See the module docs for the verification mechanism.
+    pub(crate) fn with_cup2_key(mut self, key_id: &'a str, public_key_der: &'a [u8]) -> Self {
+        self.cup2_key = Some((key_id, public_key_der));
+        self
+    }
+
+    /// Authenticates every request this `Api` makes against the cloud
+    /// server. See `Auth`.
+    pub(crate) fn with_auth(mut self, auth: Auth) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    /// Sets the backoff/retry parameters requests made by this `Api`
+    /// retry under. Defaults to a 1s base, 1 minute cap, and unlimited
+    /// retries.
+    pub(crate) fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Attaches a `system_info::Collector` whose output is merged into
+    /// `device-attributes` on every `probe`, so the server can take
+    /// live device attributes (not just the static firmware metadata)
+    /// into account.
+    pub(crate) fn with_system_info(mut self, collector: &'a crate::system_info::Collector) -> Self {
+        self.system_info = Some(collector);
+        self
+    }
+
+    /// Returns the bearer token to use for this request, performing
+    /// (and caching) an OAuth2 client-credentials grant if needed, or
+    /// transparently fetching a fresh one if the cached token expired.
+    fn access_token(&self) -> Result<Option<String>> {
+        let (client_id, client_secret, token_url) = match &self.auth {
+            Auth::None => return Ok(None),
+            Auth::Token(token) => return Ok(Some(token.clone())),
+            Auth::Credentials { client_id, client_secret, token_url } => {
+                (client_id, client_secret, token_url)
+            }
+        };
+
+        {
+            let cache = TOKEN_CACHE.lock().unwrap();
+            if let Some(cached) = cache.as_ref() {
+                if cached.expires_at > std::time::Instant::now() {
+                    return Ok(Some(cached.access_token.clone()));
+                }
+            }
+        }
+
+        #[derive(serde::Deserialize)]
+        struct TokenResponse {
+            access_token: String,
+            #[serde(default = "default_expires_in")]
+            expires_in: u64,
+        }
+        fn default_expires_in() -> u64 {
+            3600
+        }
+
+        let response: TokenResponse = attohttpc::RequestBuilder::new(attohttpc::Method::POST, token_url)
+            .connect_timeout(Duration::from_secs(10))
+            .read_timeout(Duration::from_secs(10))
+            .form(&[
+                ("grant_type", "client_credentials"),
+                ("client_id", client_id.as_str()),
+                ("client_secret", client_secret.as_str()),
+            ])?
+            .send()?
+            .json()?;
+
+        let access_token = response.access_token;
+        *TOKEN_CACHE.lock().unwrap() = Some(CachedToken {
+            access_token: access_token.clone(),
+            expires_at: std::time::Instant::now() + Duration::from_secs(response.expires_in),
+        });
+
+        Ok(Some(access_token))
+    }
+
+    /// Drops the cached access token, so the next request performs a
+    /// fresh client-credentials grant instead of reusing one the
+    /// server has stopped accepting.
+    fn invalidate_token(&self) {
+        *TOKEN_CACHE.lock().unwrap() = None;
+    }
+
+    /// Injects the `Authorization` header (if any) into every request
+    /// built by `build`, sends it, and transparently retries once with
+    /// a freshly fetched token if the server answers `401`.
+    fn send_authenticated<F>(&self, mut build: F) -> Result<attohttpc::Response>
+    where
+        F: FnMut() -> attohttpc::RequestBuilder,
+    {
+        for attempt in 0..2 {
+            let mut builder = build();
+            if let Some(token) = self.access_token()? {
+                builder = builder.header_append(AUTHORIZATION, format!("Bearer {}", token));
+            }
+
+            let response = builder.send()?;
+            if response.status() == StatusCode::UNAUTHORIZED && attempt == 0 {
+                self.invalidate_token();
+                continue;
+            }
+            return Ok(response);
+        }
+
+        unreachable!()
+    }
+
+    /// The delay before the next retry: `base * 2^attempt` capped at
+    /// `cap`, with full jitter (`random(0, delay)`) so a fleet of
+    /// devices hitting the same outage doesn't retry in lock-step.
+    fn retry_delay(&self, attempt: u32) -> Duration {
+        let backoff = self.retry.base * 2i32.saturating_pow(attempt);
+        let capped = std::cmp::min(self.retry.cap, backoff);
+        let jitter_ms = rand::thread_rng().gen_range(0, capped.num_milliseconds().max(0) + 1);
+        chrono::Duration::milliseconds(jitter_ms).to_std().unwrap_or_default()
+    }
+
+    /// Sends a request built by `build` (re-built on every attempt, so
+    /// it can bake in the current retry count), retrying on a
+    /// connection error or a `5xx`/`429` status with truncated binary
+    /// exponential backoff, up to `self.retry.max_retries`. The retry
+    /// count is persisted in `runtime_settings` across attempts and
+    /// reset on success, so it survives a restart mid-backoff.
+    fn send_with_retry<F>(
+        &self,
+        runtime_settings: &mut RuntimeSettings,
+        mut build: F,
+    ) -> Result<attohttpc::Response>
+    where
+        F: FnMut(u32) -> attohttpc::RequestBuilder,
+    {
+        let mut attempt = 0;
+        loop {
+            let retries = runtime_settings.retries();
+            let result = self.send_authenticated(|| build(retries));
+
+            let retriable = match &result {
+                Ok(response) => {
+                    response.status().is_server_error() || response.status() == StatusCode::TOO_MANY_REQUESTS
+                }
+                Err(Error::Client(_)) => true,
+                Err(_) => false,
+            };
+
+            if !retriable {
+                if result.is_ok() {
+                    runtime_settings.clear_retries();
+                }
+                return result;
+            }
+
+            if let Some(max_retries) = self.retry.max_retries {
+                if u64::from(retries) >= max_retries {
+                    return result;
+                }
+            }
+
+            runtime_settings.inc_retries();
+            std::thread::sleep(self.retry_delay(attempt));
+            attempt += 1;
+        }
     }
 
     pub async fn probe(
         &self,
-        runtime_settings: &RuntimeSettings,
+        runtime_settings: &mut RuntimeSettings,
         firmware: &Metadata,
     ) -> Result<ProbeResponse> {
-        let response = post(&format!("{}/upgrades", &self.server))
-            .header_append(HeaderName::from_static("api-retries"), runtime_settings.retries())
-            .json(&FirmwareMetadata::from_sdk(&firmware.0))?
-            .send()?;
+        let mut body = serde_json::to_value(&FirmwareMetadata::from_sdk(&firmware.0))?;
+        if let Some(collector) = self.system_info {
+            if let Some(attributes) = body.get_mut("device-attributes").and_then(|v| v.as_object_mut()) {
+                for (key, value) in collector.collect() {
+                    attributes.insert(key, serde_json::Value::String(value));
+                }
+            }
+        }
+        let body = serde_json::to_vec(&body)?;
+
+        let nonce: u32 = rand::random();
+        let url = match self.cup2_key {
+            Some((key_id, _)) => format!("{}/upgrades?cup2key={}:{}", &self.server, key_id, nonce),
+            None => format!("{}/upgrades", &self.server),
+        };
+
+        let response = self.send_with_retry(runtime_settings, |retries| {
+            post(&url)
+                .header_append(HeaderName::from_static("api-retries"), retries)
+                .bytes(body.clone())
+        })?;
 
         match response.status() {
             StatusCode::NOT_FOUND => Ok(ProbeResponse::NoUpdate),
@@ -132,13 +512,22 @@ impl<'a> Api<'a> {
                 {
                     Some(extra_poll) => Ok(ProbeResponse::ExtraPoll(extra_poll)),
                     None => {
+                        let cup2_proof =
+                            response.headers().get("X-Cup-Server-Proof").cloned();
                         let signature = response
                             .headers()
                             .get("UH-Signature")
                             .map(TryInto::try_into)
                             .transpose()?;
+                        let response_body = response.bytes()?;
+
+                        if let Some((_, public_key_der)) = self.cup2_key {
+                            let proof = cup2_proof.ok_or(Error::SignatureVerificationFailed)?;
+                            verify_cup2_response(public_key_der, &body, &response_body, proof.as_bytes())?;
+                        }
+
                         Ok(ProbeResponse::Update(
-                            UpdatePackage::parse(&response.bytes()?)?,
+                            UpdatePackage::parse(&response_body)?,
                             signature,
                         ))
                     }
@@ -150,48 +539,115 @@ impl<'a> Api<'a> {
 
     pub async fn download_object(
         &self,
+        runtime_settings: &mut RuntimeSettings,
         product_uid: &str,
         package_uid: &str,
         download_dir: &Path,
         object: &str,
+        mut progress: impl FnMut(u64, Option<u64>),
     ) -> Result<()> {
-        use std::{fs, fs::create_dir_all};
+        use std::fs::create_dir_all;
 
         // FIXME: Discuss the need of packages inside the route
-        let mut client = get(&format!(
+        let url = format!(
             "{}/products/{}/packages/{}/objects/{}",
             &self.server, product_uid, package_uid, object
-        ));
+        );
 
         if !download_dir.exists() {
             debug!("Creating directory to store the downloads.");
             create_dir_all(download_dir)?;
         }
 
-        let file = download_dir.join(object);
-        if file.exists() {
-            client = client
-                .header(RANGE, format!("bytes={}-", file.metadata()?.len().saturating_sub(1)));
+        let path = download_dir.join(object);
+        match self.download_object_attempt(runtime_settings, &url, &path, object, true, &mut progress) {
+            Err(Error::ChecksumMismatch { .. }) => {
+                // The partial file we resumed from didn't reassemble
+                // into the expected object; rather than keep resuming
+                // from a poisoned prefix forever, start over.
+                self.download_object_attempt(runtime_settings, &url, &path, object, false, &mut progress)
+            }
+            result => result,
         }
+    }
+
+    /// Downloads `object` into `path`, verifying the result against
+    /// `sha256sum` (the object's own identifier) as the response body
+    /// streams to disk. When `resume` and `path` already holds a
+    /// partial download, only the missing suffix is requested and the
+    /// existing bytes are fed into the hash alongside it; a server that
+    /// answers such a request with `200 OK` instead of `206 Partial
+    /// Content` is assumed to have ignored the range, and the download
+    /// restarts from zero.
+    fn download_object_attempt(
+        &self,
+        runtime_settings: &mut RuntimeSettings,
+        url: &str,
+        path: &Path,
+        sha256sum: &str,
+        resume: bool,
+        progress: &mut dyn FnMut(u64, Option<u64>),
+    ) -> Result<()> {
+        use std::fs;
+
+        let downloaded = if resume { path.metadata().map(|m| m.len()).unwrap_or(0) } else { 0 };
+
+        let response = self.send_with_retry(runtime_settings, |_| {
+            let mut builder = get(url);
+            if downloaded > 0 {
+                builder = builder.header(RANGE, format!("bytes={}-", downloaded));
+            }
+            builder
+        })?;
+
+        let total = response
+            .headers()
+            .get("content-length")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .map(|len| downloaded + len);
 
-        let file = fs::OpenOptions::new().create(true).append(true).open(&file)?;
-        let response = client.send()?;
-        if response.status().is_success() {
-            response.write_to(file)?;
-            return Ok(());
+        let mut hasher = Hasher::new(MessageDigest::sha256())?;
+        let file = match response.status() {
+            StatusCode::PARTIAL_CONTENT if downloaded > 0 => {
+                if content_range_start(&response)? != downloaded {
+                    return Err(Error::InvalidStatusResponse(response.status()));
+                }
+                hasher.update(&fs::read(path)?)?;
+                fs::OpenOptions::new().append(true).open(path)?
+            }
+            StatusCode::OK => fs::OpenOptions::new().create(true).write(true).truncate(true).open(path)?,
+            s => return Err(Error::InvalidStatusResponse(s)),
+        };
+
+        let mut downloaded_so_far = downloaded;
+        response.write_to(HashingWriter {
+            inner: file,
+            hasher: &mut hasher,
+            on_write: &mut |written| {
+                downloaded_so_far += written;
+                progress(downloaded_so_far, total);
+            },
+        })?;
+
+        let got = to_hex(&hasher.finish()?);
+        if got != sha256sum {
+            return Err(Error::ChecksumMismatch { expected: sha256sum.to_owned(), got });
         }
 
-        Err(Error::InvalidStatusResponse(response.status()))
+        Ok(())
     }
 
     pub async fn report(
         &self,
+        runtime_settings: &mut RuntimeSettings,
         state: &str,
         firmware: &Metadata,
         package_uid: &str,
         previous_state: Option<&str>,
         error_message: Option<String>,
         current_log: Option<String>,
+        operations: &[OperationResult],
     ) -> Result<()> {
         #[derive(Serialize)]
         #[serde(rename_all = "kebab-case")]
@@ -207,13 +663,24 @@ impl<'a> Api<'a> {
             error_message: Option<String>,
             #[serde(skip_serializing_if = "Option::is_none")]
             current_log: Option<String>,
+            #[serde(skip_serializing_if = "<[_]>::is_empty")]
+            operations: &'a [OperationResult],
         }
 
         let firmware = FirmwareMetadata::from_sdk(&firmware.0);
-        let payload =
-            Payload { state, firmware, package_uid, previous_state, error_message, current_log };
+        let payload = Payload {
+            state,
+            firmware,
+            package_uid,
+            previous_state,
+            error_message,
+            current_log,
+            operations,
+        };
+        let body = serde_json::to_vec(&payload)?;
 
-        post(&format!("{}/report", &self.server)).json(&payload)?.send()?;
+        let url = format!("{}/report", &self.server);
+        self.send_with_retry(runtime_settings, |_| post(&url).bytes(body.clone()))?;
         Ok(())
     }
 }
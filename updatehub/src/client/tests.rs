@@ -0,0 +1,96 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::{verify_cup2_response, Auth, Error};
+use openssl::{
+    ec::{EcGroup, EcKey},
+    hash::{Hasher, MessageDigest},
+    nid::Nid,
+    pkey::PKey,
+    sign::Signer,
+};
+
+fn fake_key_pair() -> (Vec<u8>, EcKey<openssl::pkey::Private>) {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    let key = EcKey::generate(&group).unwrap();
+    let public_key_der = key.public_key_to_der().unwrap();
+    (public_key_der, key)
+}
+
+fn sign(key: &EcKey<openssl::pkey::Private>, request_body: &[u8], response_body: &[u8]) -> Vec<u8> {
+    let mut request_hasher = Hasher::new(MessageDigest::sha256()).unwrap();
+    request_hasher.update(request_body).unwrap();
+    let request_digest = request_hasher.finish().unwrap();
+
+    let mut hasher = Hasher::new(MessageDigest::sha256()).unwrap();
+    hasher.update(&request_digest).unwrap();
+    hasher.update(response_body).unwrap();
+    let hash = hasher.finish().unwrap();
+
+    let pkey = PKey::from_ec_key(key.clone()).unwrap();
+    let mut signer = Signer::new(MessageDigest::sha256(), &pkey).unwrap();
+    signer.update(&hash).unwrap();
+    signer.sign_to_vec().unwrap()
+}
+
+#[test]
+fn accepts_response_matching_the_pinned_signature() {
+    let (public_key_der, key) = fake_key_pair();
+    let request_body = b"probe-request";
+    let response_body = b"probe-response";
+    let signature = sign(&key, request_body, response_body);
+    let proof = openssl::base64::encode_block(&signature);
+
+    assert!(verify_cup2_response(&public_key_der, request_body, response_body, proof.as_bytes())
+        .is_ok());
+}
+
+#[test]
+fn auth_prefers_client_credentials_over_a_static_token() {
+    let settings = crate::settings::Auth {
+        token: Some("static-token".to_string()),
+        client_id: Some("id".to_string()),
+        client_secret: Some("secret".to_string()),
+        token_url: Some("https://example.com/token".to_string()),
+    };
+
+    match Auth::from(&settings) {
+        Auth::Credentials { client_id, client_secret, token_url } => {
+            assert_eq!(client_id, "id");
+            assert_eq!(client_secret, "secret");
+            assert_eq!(token_url, "https://example.com/token");
+        }
+        other => panic!("expected client-credentials auth, got {:?}", other),
+    }
+}
+
+#[test]
+fn auth_falls_back_to_a_static_token() {
+    let settings = crate::settings::Auth { token: Some("static-token".to_string()), ..Default::default() };
+
+    match Auth::from(&settings) {
+        Auth::Token(token) => assert_eq!(token, "static-token"),
+        other => panic!("expected a static token auth, got {:?}", other),
+    }
+}
+
+#[test]
+fn auth_is_none_when_unconfigured() {
+    assert!(matches!(Auth::from(&crate::settings::Auth::default()), Auth::None));
+}
+
+#[test]
+fn rejects_a_tampered_response_body() {
+    let (public_key_der, key) = fake_key_pair();
+    let request_body = b"probe-request";
+    let response_body = b"probe-response";
+    let signature = sign(&key, request_body, response_body);
+    let proof = openssl::base64::encode_block(&signature);
+
+    let tampered_body = b"tampered-response";
+    match verify_cup2_response(&public_key_der, request_body, tampered_body, proof.as_bytes()) {
+        Err(Error::SignatureVerificationFailed) => {}
+        other => panic!("expected a signature verification failure, got {:?}", other),
+    }
+}
@@ -0,0 +1,73 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A typed, in-process subscription API for state-machine activity, so
+//! a consumer (e.g. the HTTP API) can stream live status instead of
+//! parsing `logger::get_memory_log()` lines.
+//!
+//! `run_with_observers` registers a set of `StateObserver`s before
+//! starting the state machine; every state transition, download
+//! progress update and terminal error is then fanned out to all of
+//! them.
+
+use std::sync::Mutex;
+
+/// Mirrors `states::State`'s variants (minus `Error`, which is
+/// reported through `StateObserver::on_error` instead), so observers
+/// don't need to depend on the state machine's internal types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateId {
+    Park,
+    EntryPoint,
+    Poll,
+    Probe,
+    Validation,
+    PrepareDownload,
+    Download,
+    Install,
+    Reboot,
+    DirectDownload,
+    PrepareLocalInstall,
+}
+
+/// Subscribes to state-machine activity. Every method has a no-op
+/// default, so an observer only needs to implement the callbacks it
+/// cares about.
+pub trait StateObserver: Send {
+    fn on_state_enter(&self, _state: StateId) {}
+
+    /// `bytes_total` is `None` until the server's `Content-Length` for
+    /// the object currently downloading is known.
+    fn on_download_progress(&self, _package_uid: &str, _bytes_done: u64, _bytes_total: Option<u64>) {}
+
+    fn on_error(&self, _error: &str) {}
+}
+
+lazy_static::lazy_static! {
+    static ref OBSERVERS: Mutex<Vec<Box<dyn StateObserver>>> = Mutex::new(Vec::new());
+}
+
+/// Replaces the globally-registered observers. Called once by
+/// `run_with_observers` before the state machine starts.
+pub(crate) fn set_observers(observers: Vec<Box<dyn StateObserver>>) {
+    *OBSERVERS.lock().unwrap() = observers;
+}
+
+pub(crate) fn notify_state_enter(state: StateId) {
+    for observer in OBSERVERS.lock().unwrap().iter() {
+        observer.on_state_enter(state);
+    }
+}
+
+pub(crate) fn notify_download_progress(package_uid: &str, bytes_done: u64, bytes_total: Option<u64>) {
+    for observer in OBSERVERS.lock().unwrap().iter() {
+        observer.on_download_progress(package_uid, bytes_done, bytes_total);
+    }
+}
+
+pub(crate) fn notify_error(error: &str) {
+    for observer in OBSERVERS.lock().unwrap().iter() {
+        observer.on_error(error);
+    }
+}
@@ -0,0 +1,87 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Computes when the stepper should wake up for the next probe.
+//!
+//! A fleet of devices rebooting at once would otherwise hammer the
+//! server in lock-step, so every scheduled delay gets uniform jitter
+//! applied. A failed probe additionally backs off exponentially,
+//! capped at the configured interval; `consecutive_failures` (and the
+//! last poll time) are expected to be persisted in `RuntimeSettings`
+//! so backoff survives restarts.
+
+use chrono::Duration;
+use rand::Rng;
+
+pub(crate) struct Policy {
+    pub(crate) interval: Duration,
+    pub(crate) fuzz: f64,
+}
+
+impl Policy {
+    pub(crate) fn new(interval: Duration, fuzz: f64) -> Self {
+        Self { interval, fuzz }
+    }
+
+    /// The delay to sleep before the next probe. On a successful
+    /// probe (`consecutive_failures == 0`) this is `interval` plus
+    /// jitter; after failures it is an exponentially increasing delay,
+    /// capped at `interval`, plus the same jitter.
+    pub(crate) fn next_delay(&self, consecutive_failures: u32) -> Duration {
+        let base = if consecutive_failures == 0 {
+            self.interval
+        } else {
+            // The smallest exponential-backoff step, doubled per
+            // consecutive failure; matches the minimum accepted
+            // `Polling.Interval`.
+            let backoff_base = Duration::seconds(60);
+            let backoff = backoff_base * 2i32.saturating_pow(consecutive_failures);
+            std::cmp::min(self.interval, backoff)
+        };
+
+        self.jitter(base)
+    }
+
+    fn jitter(&self, base: Duration) -> Duration {
+        if self.fuzz <= 0.0 {
+            return base;
+        }
+
+        let bound = (base.num_milliseconds() as f64 * self.fuzz) as i64;
+        let offset = rand::thread_rng().gen_range(-bound, bound + 1);
+        base + Duration::milliseconds(offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn successful_probe_stays_within_jittered_interval() {
+        let policy = Policy::new(Duration::seconds(100), 0.1);
+
+        for _ in 0..100 {
+            let delay = policy.next_delay(0);
+            assert!(delay >= Duration::seconds(90));
+            assert!(delay <= Duration::seconds(110));
+        }
+    }
+
+    #[test]
+    fn failed_probes_back_off_exponentially_up_to_the_interval() {
+        let policy = Policy::new(Duration::seconds(1000), 0.0);
+
+        assert_eq!(policy.next_delay(1), Duration::seconds(120));
+        assert_eq!(policy.next_delay(2), Duration::seconds(240));
+        assert_eq!(policy.next_delay(3), Duration::seconds(480));
+        assert_eq!(policy.next_delay(20), Duration::seconds(1000));
+    }
+
+    #[test]
+    fn zero_fuzz_returns_the_exact_interval() {
+        let policy = Policy::new(Duration::seconds(100), 0.0);
+        assert_eq!(policy.next_delay(0), Duration::seconds(100));
+    }
+}
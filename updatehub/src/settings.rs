@@ -0,0 +1,370 @@
+// Copyright (C) 2018 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use chrono::Duration;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("invalid settings: {0}")]
+    Ini(#[from] serde_ini::de::Error),
+
+    #[error("invalid settings: {0}")]
+    Toml(#[from] toml::de::Error),
+
+    #[error("invalid polling interval")]
+    InvalidInterval,
+
+    #[error("invalid server address")]
+    InvalidServerAddress,
+
+    #[error("invalid auth settings: ClientId, ClientSecret and TokenUrl must all be set")]
+    InvalidAuth,
+
+    #[error("invalid CUP2 settings: Cup2Key and Cup2KeyId must be set together")]
+    InvalidCup2Key,
+}
+
+/// The format a settings file is written in, picked by `Settings::load`
+/// from the file's extension (`.toml` vs anything else, which is
+/// assumed to be the legacy INI format).
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Format {
+    Ini,
+    Toml,
+}
+
+impl Format {
+    fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(std::ffi::OsStr::to_str) {
+            Some("toml") => Format::Toml,
+            _ => Format::Ini,
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct Settings {
+    pub(crate) firmware: Firmware,
+    pub(crate) network: Network,
+    pub(crate) polling: Polling,
+    pub(crate) storage: Storage,
+}
+
+impl Settings {
+    /// Loads the settings from `path`. If it does not exist, it uses
+    /// the default settings. Every section and field is optional:
+    /// whatever is present in `path` is merged over `Settings::default`,
+    /// so a minimal config that only overrides, say, `Polling.Interval`
+    /// keeps working even after new fields are added in later releases.
+    pub fn load(path: &Path) -> Result<Self> {
+        use std::{fs::File, io::Read};
+
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let mut content = String::new();
+        File::open(path)?.read_to_string(&mut content)?;
+        Self::parse(&content, Format::from_path(path))
+    }
+
+    fn parse(content: &str, format: Format) -> Result<Self> {
+        let partial = match format {
+            Format::Ini => serde_ini::from_str::<PartialSettings>(content)?,
+            Format::Toml => toml::from_str::<PartialSettings>(content)?,
+        };
+        let settings = partial.merge_over(Self::default());
+
+        if settings.polling.interval < Duration::seconds(60) {
+            return Err(Error::InvalidInterval);
+        }
+
+        if !settings.network.server_address.starts_with("http://")
+            && !settings.network.server_address.starts_with("https://")
+        {
+            return Err(Error::InvalidServerAddress);
+        }
+
+        if !settings.network.auth.is_valid() {
+            return Err(Error::InvalidAuth);
+        }
+
+        if settings.network.cup2_key.is_some() != settings.network.cup2_key_id.is_some() {
+            return Err(Error::InvalidCup2Key);
+        }
+
+        Ok(settings)
+    }
+}
+
+/// Mirrors `Settings`, but every section and field is optional so a
+/// config missing either doesn't fail to deserialize; `merge_over`
+/// then applies whatever is present on top of a base `Settings`.
+#[derive(Debug, Default, Deserialize, Clone)]
+#[serde(rename_all = "PascalCase")]
+struct PartialSettings {
+    firmware: Option<PartialFirmware>,
+    network: Option<PartialNetwork>,
+    polling: Option<PartialPolling>,
+    storage: Option<PartialStorage>,
+}
+
+impl PartialSettings {
+    fn merge_over(self, base: Settings) -> Settings {
+        Settings {
+            firmware: self.firmware.map(|p| p.merge_over(base.firmware.clone())).unwrap_or(base.firmware),
+            network: self.network.map(|p| p.merge_over(base.network.clone())).unwrap_or(base.network),
+            polling: self.polling.map(|p| p.merge_over(base.polling.clone())).unwrap_or(base.polling),
+            storage: self.storage.map(|p| p.merge_over(base.storage.clone())).unwrap_or(base.storage),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct Network {
+    pub server_address: String,
+    pub listen_socket: String,
+    #[serde(flatten)]
+    pub auth: Auth,
+    /// DER-encoded, base64 P-256 ECDSA public key pinned to verify
+    /// CUPv2-style signed probe/update responses against. Leaving this
+    /// (and `cup2_key_id`) unset disables response verification.
+    pub cup2_key: Option<String>,
+    /// Identifies which pinned key a response was signed with; sent
+    /// to the server as part of the `cup2key` query parameter.
+    pub cup2_key_id: Option<String>,
+    /// Base delay for the truncated binary exponential backoff applied
+    /// between retries of a failed cloud request.
+    pub backoff_base: Duration,
+    /// Upper bound the backoff delay is capped at, regardless of how
+    /// many consecutive retries have happened.
+    pub backoff_cap: Duration,
+    /// How many times a retriable cloud request failure is retried
+    /// before giving up. `None` retries forever.
+    pub max_retries: Option<u64>,
+}
+
+impl Default for Network {
+    fn default() -> Self {
+        Self {
+            server_address: "https://api.updatehub.io".to_string(),
+            listen_socket: "localhost:8080".to_string(),
+            auth: Auth::default(),
+            cup2_key: None,
+            cup2_key_id: None,
+            backoff_base: Duration::seconds(1),
+            backoff_cap: Duration::minutes(1),
+            max_retries: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Clone)]
+#[serde(rename_all = "PascalCase")]
+struct PartialNetwork {
+    server_address: Option<String>,
+    listen_socket: Option<String>,
+    #[serde(flatten)]
+    auth: PartialAuth,
+    cup2_key: Option<String>,
+    cup2_key_id: Option<String>,
+    backoff_base: Option<Duration>,
+    backoff_cap: Option<Duration>,
+    max_retries: Option<u64>,
+}
+
+impl PartialNetwork {
+    fn merge_over(self, base: Network) -> Network {
+        Network {
+            server_address: self.server_address.unwrap_or(base.server_address),
+            listen_socket: self.listen_socket.unwrap_or(base.listen_socket),
+            auth: self.auth.merge_over(base.auth),
+            cup2_key: self.cup2_key.or(base.cup2_key),
+            cup2_key_id: self.cup2_key_id.or(base.cup2_key_id),
+            backoff_base: self.backoff_base.unwrap_or(base.backoff_base),
+            backoff_cap: self.backoff_cap.unwrap_or(base.backoff_cap),
+            max_retries: self.max_retries.or(base.max_retries),
+        }
+    }
+}
+
+/// Credentials used to authenticate requests the agent's HTTP API
+/// receives. Leaving every field empty (the default) disables
+/// authentication.
+///
+/// `ClientId`/`ClientSecret`/`TokenUrl` make the agent perform an
+/// OAuth2 client-credentials grant and cache the resulting access
+/// token; `Token` is a static bearer token fallback used as-is when
+/// no client-credentials are configured.
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct Auth {
+    pub token: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub token_url: Option<String>,
+}
+
+impl Auth {
+    /// An `Auth` is valid when it is left entirely empty (no auth) or
+    /// when the client-credentials triple is set together.
+    fn is_valid(&self) -> bool {
+        match (&self.client_id, &self.client_secret, &self.token_url) {
+            (None, None, None) => true,
+            (Some(_), Some(_), Some(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+type PartialAuth = Auth;
+
+impl PartialAuth {
+    fn merge_over(self, base: Auth) -> Auth {
+        Auth {
+            token: self.token.or(base.token),
+            client_id: self.client_id.or(base.client_id),
+            client_secret: self.client_secret.or(base.client_secret),
+            token_url: self.token_url.or(base.token_url),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct Polling {
+    pub interval: Duration,
+    pub enabled: bool,
+    /// Fraction of `interval` used as the +/- bound for the random
+    /// jitter applied to every scheduled poll, so a fleet rebooted at
+    /// once doesn't hammer the server in lock-step. Defaults to 0.1
+    /// (+/-10%). See `crate::polling::Policy`.
+    pub fuzz: f64,
+}
+
+impl Default for Polling {
+    fn default() -> Self {
+        Self { interval: Duration::days(1), enabled: true, fuzz: 0.1 }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Clone)]
+#[serde(rename_all = "PascalCase")]
+struct PartialPolling {
+    interval: Option<Duration>,
+    enabled: Option<bool>,
+    fuzz: Option<f64>,
+}
+
+impl PartialPolling {
+    fn merge_over(self, base: Polling) -> Polling {
+        Polling {
+            interval: self.interval.unwrap_or(base.interval),
+            enabled: self.enabled.unwrap_or(base.enabled),
+            fuzz: self.fuzz.unwrap_or(base.fuzz),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct Storage {
+    pub read_only: bool,
+    pub runtime_settings: PathBuf,
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self { read_only: false, runtime_settings: "/var/lib/updatehub/runtime_settings.conf".into() }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Clone)]
+#[serde(rename_all = "PascalCase")]
+struct PartialStorage {
+    read_only: Option<bool>,
+    runtime_settings: Option<PathBuf>,
+}
+
+impl PartialStorage {
+    fn merge_over(self, base: Storage) -> Storage {
+        Storage {
+            read_only: self.read_only.unwrap_or(base.read_only),
+            runtime_settings: self.runtime_settings.unwrap_or(base.runtime_settings),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq, Serialize, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct Firmware {
+    pub metadata: PathBuf,
+    /// Executable (or directory of scripts, run in lexical order) that
+    /// the agent runs at probe time to collect live device attributes
+    /// (running kernel, free storage, boot count, ...), parsing stdout
+    /// as a flat key/value JSON object. Unset disables system-info
+    /// collection. See `crate::system_info`.
+    pub system_info: Option<PathBuf>,
+    /// How long a collected system-info snapshot is reused before the
+    /// scripts are run again. Defaults to 5 minutes.
+    pub system_info_timeout: Duration,
+}
+
+impl Default for Firmware {
+    fn default() -> Self {
+        Self {
+            metadata: "/usr/share/updatehub".into(),
+            system_info: None,
+            system_info_timeout: Duration::minutes(5),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Clone)]
+#[serde(rename_all = "PascalCase")]
+struct PartialFirmware {
+    metadata: Option<PathBuf>,
+    system_info: Option<PathBuf>,
+    system_info_timeout: Option<Duration>,
+}
+
+impl PartialFirmware {
+    fn merge_over(self, base: Firmware) -> Firmware {
+        Firmware {
+            metadata: self.metadata.unwrap_or(base.metadata),
+            system_info: self.system_info.or(base.system_info),
+            system_info_timeout: self.system_info_timeout.unwrap_or(base.system_info_timeout),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toml_with_only_polling_interval_falls_back_to_defaults() {
+        let settings = Settings::parse("[Polling]\nInterval = 3600\n", Format::Toml).unwrap();
+
+        assert_eq!(settings.polling.interval, Duration::seconds(3600));
+        assert_eq!(settings.network, Network::default());
+        assert_eq!(settings.storage, Storage::default());
+        assert_eq!(settings.firmware, Firmware::default());
+    }
+
+    #[test]
+    fn empty_toml_is_equivalent_to_default() {
+        assert_eq!(Settings::parse("", Format::Toml).unwrap(), Settings::default());
+    }
+}
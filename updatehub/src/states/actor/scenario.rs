@@ -0,0 +1,165 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! A data-driven replacement for the old hand-written actor tests
+//! (`step_sequence`, `download_abort`, `trigger_probe`): a scenario is a
+//! sequence of actions sent to a `Machine`, each checked against the
+//! `info::Request` state it leaves behind and, optionally, against how
+//! long the transition took. Scenarios live as plain JSON fixtures
+//! under `fixtures/`, so a new one can be added without touching any
+//! Rust code, and `load_scenarios`'s `filter` lets a developer run just
+//! one of them.
+
+use super::*;
+use futures::future::{self, Future};
+use serde::Deserialize;
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Action {
+    Step,
+    Probe,
+    DownloadAbort,
+    Info,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct ScenarioStep {
+    action: Action,
+    expect_state: String,
+    max_duration_ms: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct Scenario {
+    pub(crate) name: String,
+    #[serde(default)]
+    steps: Vec<ScenarioStep>,
+
+    /// When set, the fixture isn't meant for `run_scenario` at all:
+    /// it's meant for `assert_step_count`, which drives a bare
+    /// `FakeMachine` through exactly this many `Step` messages and
+    /// checks it answers `StepTransition::Never` on the last one.
+    #[serde(default)]
+    pub(crate) expected_step_count: Option<usize>,
+}
+
+/// Loads every `*.json` fixture under `dir`, keeping only the ones
+/// whose `name` matches `filter` when one is given.
+pub(crate) fn load_scenarios(dir: &Path, filter: Option<&str>) -> Result<Vec<Scenario>, failure::Error> {
+    let mut scenarios = Vec::new();
+
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_fixture = path.file_name().and_then(|n| n.to_str()).map_or(false, |n| n.ends_with(".json"));
+        if !is_fixture {
+            continue;
+        }
+
+        let json = std::fs::read_to_string(&path)?;
+
+        let scenario: Scenario = serde_json::from_str(&json)?;
+        if filter.map_or(true, |name| scenario.name == name) {
+            scenarios.push(scenario);
+        }
+    }
+
+    scenarios.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(scenarios)
+}
+
+/// Drives `addr` through every action in `scenario`, asserting the
+/// resulting state after each one and, when the fixture set a budget,
+/// that the transition didn't take longer than `max_duration_ms`.
+pub(crate) fn run_scenario(
+    addr: Addr<Machine>,
+    scenario: Scenario,
+) -> impl Future<Item = (), Error = failure::Error> {
+    let name = scenario.name;
+
+    futures::stream::iter_ok(scenario.steps)
+        .fold(addr, move |addr, step| {
+            let name = name.clone();
+            let started = Instant::now();
+
+            let transition: Box<dyn Future<Item = Addr<Machine>, Error = failure::Error>> = match step.action {
+                Action::Step => Box::new(addr.send(Step).map(|_| addr.clone()).map_err(Into::into)),
+                Action::Probe => {
+                    Box::new(addr.send(probe::Request(None)).map(|_| addr.clone()).map_err(Into::into))
+                }
+                Action::DownloadAbort => {
+                    Box::new(addr.send(download_abort::Request).map(|_| addr.clone()).map_err(Into::into))
+                }
+                Action::Info => Box::new(future::ok(addr.clone())),
+            };
+
+            transition.and_then(move |addr| {
+                addr.send(info::Request).map_err(failure::Error::from).map(move |response| {
+                    assert_eq!(
+                        response.state, step.expect_state,
+                        "scenario '{}': expected state '{}', got '{}'",
+                        name, step.expect_state, response.state
+                    );
+                    if let Some(max_ms) = step.max_duration_ms {
+                        let elapsed = started.elapsed();
+                        assert!(
+                            elapsed <= Duration::from_millis(max_ms),
+                            "scenario '{}': transition took {:?}, over the {}ms budget",
+                            name,
+                            elapsed,
+                            max_ms
+                        );
+                    }
+                    addr
+                })
+            })
+        })
+        .map(|_| ())
+}
+
+#[derive(Default)]
+pub(crate) struct FakeMachine {
+    step_count: usize,
+    step_expect: usize,
+}
+
+impl Actor for FakeMachine {
+    type Context = Context<Self>;
+
+    // Only the stepper holds a reference to this Actor's Addr, so once it
+    // stops sending Step messages the system can be shut down and we can
+    // assert the number of steps actually received.
+    fn stopped(&mut self, _: &mut Context<Self>) {
+        assert_eq!(self.step_count, self.step_expect);
+        System::current().stop();
+    }
+}
+
+impl Handler<Step> for FakeMachine {
+    type Result = MessageResult<Step>;
+
+    fn handle(&mut self, _: Step, _: &mut Context<Self>) -> Self::Result {
+        self.step_count += 1;
+        if self.step_count >= self.step_expect {
+            MessageResult(StepTransition::Never)
+        } else {
+            MessageResult(StepTransition::Immediate)
+        }
+    }
+}
+
+/// Drives a `FakeMachine` expecting exactly `step_count` steps through
+/// `stepper::Controller`; `FakeMachine::stopped`'s assertion is what
+/// actually checks the count once the stepper gives up and the system
+/// stops.
+pub(crate) fn assert_step_count(step_count: usize) {
+    let mock = actix::Actor::start(FakeMachine { step_expect: step_count, ..FakeMachine::default() });
+    let mut stepper = stepper::Controller::default();
+
+    stepper.start(mock);
+}
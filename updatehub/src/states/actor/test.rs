@@ -15,47 +15,22 @@ use crate::{
 use actix::{Addr, Arbiter, System};
 use futures::future::{self, Future};
 use pretty_assertions::assert_eq;
-use std::fs;
+use std::{fs, path::Path};
 
+#[derive(Clone, Copy)]
 enum Setup {
     HasUpdate,
     NoUpdate,
 }
 
+#[derive(Clone, Copy)]
 enum Probe {
     Enabled,
     Disabled,
 }
 
-#[derive(Default)]
-struct FakeMachine {
-    step_count: usize,
-    step_expect: usize,
-}
-
-impl Actor for FakeMachine {
-    type Context = Context<Self>;
-
-    // In tests, only one reference to the Actor's Addr is held, and it is held by
-    // the stepper, when it stops the system can be shutdown and we can assert the
-    // number of steppers received
-    fn stopped(&mut self, _: &mut Context<Self>) {
-        assert_eq!(self.step_count, self.step_expect);
-        System::current().stop();
-    }
-}
-
-impl Handler<Step> for FakeMachine {
-    type Result = MessageResult<Step>;
-
-    fn handle(&mut self, _: Step, _: &mut Context<Self>) -> Self::Result {
-        self.step_count += 1;
-        if self.step_count >= self.step_expect {
-            MessageResult(super::StepTransition::Never)
-        } else {
-            MessageResult(super::StepTransition::Immediate)
-        }
-    }
+fn fixtures_dir() -> std::path::PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("src/states/actor/fixtures")
 }
 
 fn setup_actor(kind: Setup, probe: Probe) -> (Addr<Machine>, mockito::Mock, Settings, Metadata) {
@@ -120,125 +95,58 @@ fn info_request() {
     system.run().unwrap();
 }
 
+/// Scripted replacement for the old hand-written `step_sequence`,
+/// `download_abort` and `trigger_probe` tests: each is now a plain
+/// JSON fixture under `fixtures/`, driven through `scenario::run_scenario`.
+/// Pass a scenario's name to `load_scenarios`'s `filter` to run a single
+/// one instead of the whole set.
 #[test]
-fn step_sequence() {
-    let system = System::new("test");
-
-    let (addr, mock, ..) = setup_actor(Setup::NoUpdate, Probe::Enabled);
-    Arbiter::spawn(
-        addr.send(info::Request)
-            .map(move |response| {
-                assert_eq!(response.state, "idle");
-                addr
-            })
-            .and_then(|addr| {
-                let f1 = addr.send(Step);
-                let f2 = addr
-                    .send(info::Request)
-                    .map(|res| assert_eq!(res.state, "poll"));
-                f1.then(|_| f2).then(|_| future::ok(addr))
-            })
-            .and_then(|addr| {
-                let f1 = addr.send(Step);
-                let f2 = addr
-                    .send(info::Request)
-                    .map(|res| assert_eq!(res.state, "probe"));
-                f1.then(|_| f2).then(|_| future::ok(addr))
-            })
-            .and_then(|addr| {
-                let f1 = addr.send(Step);
-                let f2 = addr
-                    .send(info::Request)
-                    .map(|res| assert_eq!(res.state, "idle"));
-                f1.then(|_| f2).then(|_| future::ok(addr))
-            })
-            .then(move |_| {
-                mock.assert();
-                System::current().stop();
-                future::ok(())
-            }),
-    );
-
-    system.run().unwrap();
-}
-
-#[test]
-fn download_abort() {
+fn scripted_scenarios() {
     let system = System::new("test");
-
-    let (addr, mock, ..) = setup_actor(Setup::HasUpdate, Probe::Enabled);
-    Arbiter::spawn(
-        future::ok::<_, failure::Error>(addr)
-            .and_then(|addr| {
-                let f1 = addr.send(Step);
-                let f2 = addr.send(Step);
-                let f3 = addr.send(Step);
-                let f4 = addr
-                    .send(info::Request)
-                    .map(|res| assert_eq!(res.state, "prepare_download"));
-                f1.then(|_| f2)
-                    .then(|_| f3)
-                    .then(|_| f4)
-                    .then(|_| future::ok(addr))
-            })
-            .and_then(|addr| {
-                let f1 = addr.send(download_abort::Request);
-                let f2 = addr
-                    .send(info::Request)
-                    .map(|res| assert_eq!(res.state, "idle"));
-                f1.then(|_| f2).then(|_| future::ok(addr))
-            })
-            .then(move |_| {
-                mock.assert();
-                System::current().stop();
-                future::ok(())
-            }),
-    );
-
-    system.run().unwrap();
-}
-
-#[test]
-fn trigger_probe() {
-    let system = System::new("test");
-
-    let (addr, ..) = setup_actor(Setup::NoUpdate, Probe::Disabled);
-    Arbiter::spawn(
-        future::ok::<_, failure::Error>(addr)
-            .and_then(|addr| {
-                let f1 = addr.send(Step);
-                let f2 = addr
-                    .send(info::Request)
-                    .map(|res| assert_eq!(res.state, "park"));
-                f1.then(|_| f2).then(|_| future::ok(addr))
-            })
-            .and_then(|addr| {
-                let f1 = addr.send(probe::Request(None));
-                let f2 = addr
-                    .send(info::Request)
-                    .map(|res| assert_eq!(res.state, "probe"));
-                f1.then(|_| f2).then(|_| future::ok(addr))
-            })
-            .then(move |_| {
-                System::current().stop();
-                future::ok(())
-            }),
-    );
+    let dir = fixtures_dir();
+
+    let runs = [
+        ("step_sequence", Setup::NoUpdate, Probe::Enabled),
+        ("download_abort", Setup::HasUpdate, Probe::Enabled),
+        ("trigger_probe", Setup::NoUpdate, Probe::Disabled),
+    ]
+    .iter()
+    .map(|&(name, setup, probe)| {
+        let (addr, mock, ..) = setup_actor(setup, probe);
+        let scenario = scenario::load_scenarios(&dir, Some(name))
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| panic!("missing fixture for scenario '{}'", name));
+
+        scenario::run_scenario(addr, scenario).map(move |_| mock.assert())
+    })
+    .collect::<Vec<_>>();
+
+    Arbiter::spawn(future::join_all(runs).then(|result| {
+        result.unwrap();
+        System::current().stop();
+        future::ok(())
+    }));
 
     system.run().unwrap();
 }
 
+/// Exercises the same `FakeMachine`/`StepTransition::Never` plumbing the
+/// old `stepper_with_never` test used directly, but with the expected
+/// step count pulled from the `step_limit` fixture.
 #[test]
 fn stepper_with_never() {
     let system = System::new("test");
 
-    let mock = actix::Actor::start(FakeMachine {
-        step_expect: 15,
-        ..FakeMachine::default()
-    });
-    let mut stepper = super::stepper::Controller::default();
+    let scenario = scenario::load_scenarios(&fixtures_dir(), Some("step_limit"))
+        .unwrap()
+        .pop()
+        .expect("missing step_limit fixture");
+    let step_count = scenario
+        .expected_step_count
+        .expect("step_limit fixture must set expected_step_count");
 
-    stepper.start(mock);
+    scenario::assert_step_count(step_count);
 
     system.run().unwrap();
 }
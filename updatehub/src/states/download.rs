@@ -100,12 +100,20 @@ impl StateChangeImpl for State<Download> {
                 object::info::Status::Incomplete,
             ))
         {
-            Api::new(&shared_state!().settings.network.server_address).download_object(
-                &shared_state!().firmware.product_uid,
-                &self.0.update_package.package_uid(),
-                download_dir,
-                object.sha256sum(),
-            )?;
+            let package_uid = self.0.update_package.package_uid();
+            Api::new(&shared_state!().settings.network.server_address)
+                .with_auth(crate::client::Auth::from(&shared_state!().settings.network.auth))
+                .with_retry_policy(crate::client::RetryPolicy::from(&shared_state!().settings.network))
+                .download_object(
+                    &mut shared_state_mut!().runtime_settings,
+                    &shared_state!().firmware.product_uid,
+                    &package_uid,
+                    download_dir,
+                    object.sha256sum(),
+                    |bytes_done, bytes_total| {
+                        crate::observer::notify_download_progress(&package_uid, bytes_done, bytes_total)
+                    },
+                )?;
         }
 
         if self
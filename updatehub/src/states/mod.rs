@@ -30,6 +30,7 @@ use self::{
 use crate::{
     firmware::{self, Metadata, Transition},
     http_api,
+    observer::{self, StateId},
     runtime_settings::RuntimeSettings,
     settings::Settings,
 };
@@ -108,6 +109,14 @@ trait ProgressReporter: Sized + StateChangeImpl {
     fn report_enter_state_name(&self) -> &'static str;
     fn report_leave_state_name(&self) -> &'static str;
 
+    /// Per-object install/validation outcomes to attach to the next
+    /// report, so the server can tell which object in a multi-object
+    /// package failed and why. States that don't install objects
+    /// (e.g. `Download`) report none.
+    fn operation_results(&self) -> Vec<crate::client::OperationResult> {
+        Vec::new()
+    }
+
     async fn handle_and_report_progress(
         self,
         shared_state: &mut machine::SharedState,
@@ -117,35 +126,54 @@ trait ProgressReporter: Sized + StateChangeImpl {
         let package_uid = &self.package_uid();
         let enter_state = self.report_enter_state_name();
         let leave_state = self.report_leave_state_name();
-        let api = crate::CloudClient::new(&server);
-
-        let report = |state, previous_state, error_message, current_log| {
+        let api = crate::CloudClient::new(&server)
+            .with_auth(crate::client::Auth::from(&shared_state.settings.network.auth))
+            .with_retry_policy(crate::client::RetryPolicy::from(&shared_state.settings.network));
+
+        let report = |shared_state: &mut machine::SharedState,
+                       state,
+                       previous_state,
+                       error_message,
+                       current_log,
+                       operations: &[crate::client::OperationResult]| {
             api.report(
+                &mut shared_state.runtime_settings,
                 state,
                 firmware.as_cloud_metadata(),
                 package_uid,
                 previous_state,
                 error_message,
                 current_log,
+                operations,
             )
         };
 
-        if let Err(e) = report(enter_state, None, None, None).await {
+        // Captured before `self` is consumed by `handle` below, so this
+        // only covers results already known on entry; a state that
+        // builds up per-object results while handling itself (e.g. a
+        // multi-object install) should report those itself before
+        // returning.
+        let operations_on_entry = self.operation_results();
+
+        if let Err(e) = report(shared_state, enter_state, None, None, None, &operations_on_entry).await {
             warn!("report failed: {}", e);
         }
         match self.handle(shared_state).await {
             Ok((state, trans)) => {
-                if let Err(e) = report(leave_state, None, None, None).await {
+                if let Err(e) = report(shared_state, leave_state, None, None, None, &[]).await {
                     warn!("report failed: {}", e);
                 };
                 Ok((state, trans))
             }
             Err(e) => {
+                observer::notify_error(&e.to_string());
                 if let Err(e) = report(
+                    shared_state,
                     "error",
                     Some(enter_state),
                     Some(e.to_string()),
                     Some(crate::logger::get_memory_log()),
+                    &[],
                 )
                 .await
                 {
@@ -243,6 +271,10 @@ impl State {
         self,
         shared_state: &mut machine::SharedState,
     ) -> Result<(Self, machine::StepTransition)> {
+        if let Some(state_id) = self.state_id() {
+            observer::notify_state_enter(state_id);
+        }
+
         match self {
             State::Error(s) => s.handle(shared_state).await,
             State::Park(s) => s.handle(shared_state).await,
@@ -259,6 +291,25 @@ impl State {
         }
     }
 
+    /// `None` for `State::Error`, which is reported through
+    /// `StateObserver::on_error` instead of `on_state_enter`.
+    fn state_id(&self) -> Option<StateId> {
+        Some(match self {
+            State::Error(_) => return None,
+            State::Park(_) => StateId::Park,
+            State::EntryPoint(_) => StateId::EntryPoint,
+            State::Poll(_) => StateId::Poll,
+            State::Probe(_) => StateId::Probe,
+            State::Validation(_) => StateId::Validation,
+            State::PrepareDownload(_) => StateId::PrepareDownload,
+            State::Download(_) => StateId::Download,
+            State::Install(_) => StateId::Install,
+            State::Reboot(_) => StateId::Reboot,
+            State::DirectDownload(_) => StateId::DirectDownload,
+            State::PrepareLocalInstall(_) => StateId::PrepareLocalInstall,
+        })
+    }
+
     fn inner_state(&self) -> &dyn StateChangeImpl {
         match self {
             State::Error(s) => s,
@@ -309,6 +360,18 @@ impl State {
 /// # }
 /// ```
 pub async fn run(settings: &Path) -> crate::Result<()> {
+    run_with_observers(settings, Vec::new()).await
+}
+
+/// Same as `run`, but fans out every state transition, download
+/// progress update and terminal error to `observers` as it happens,
+/// so a caller can drive a live status feed instead of tailing logs.
+pub async fn run_with_observers(
+    settings: &Path,
+    observers: Vec<Box<dyn observer::StateObserver>>,
+) -> crate::Result<()> {
+    observer::set_observers(observers);
+
     crate::logger::start_memory_logging();
     let settings = Settings::load(settings)?;
     let listen_socket = settings.network.listen_socket.clone();
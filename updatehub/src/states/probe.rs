@@ -0,0 +1,107 @@
+// Copyright (C) 2018 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+use super::{machine, EntryPoint, Result, State, StateChangeImpl, Validation};
+use crate::client::ProbeResponse;
+use async_trait::async_trait;
+use slog_scope::{debug, info};
+
+/// Sent to `machine::StateMachine` to trigger a probe outside the
+/// normal poll cadence (e.g. from the HTTP API). `force` is threaded
+/// into the resulting `Probe` state to bypass its
+/// already-applied-package short-circuit, so an operator-triggered
+/// probe always reaches the server even if nothing would change on
+/// the device.
+#[derive(Debug)]
+pub(crate) struct Request {
+    pub(crate) force: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub(super) struct Probe {
+    pub(super) force: bool,
+}
+
+#[async_trait(?Send)]
+impl StateChangeImpl for Probe {
+    fn name(&self) -> &'static str {
+        "probe"
+    }
+
+    async fn handle(
+        self,
+        shared_state: &mut machine::SharedState,
+    ) -> Result<(State, machine::StepTransition)> {
+        let server = shared_state.server_address().to_owned();
+        let api = crate::CloudClient::new(&server)
+            .with_auth(crate::client::Auth::from(&shared_state.settings.network.auth))
+            .with_retry_policy(crate::client::RetryPolicy::from(&shared_state.settings.network))
+            .with_system_info(&shared_state.system_info);
+
+        let idle = || (State::EntryPoint(EntryPoint {}), machine::StepTransition::Immediate);
+
+        match api.probe(&mut shared_state.runtime_settings, &shared_state.firmware).await? {
+            ProbeResponse::NoUpdate => {
+                debug!("no update available");
+                shared_state.runtime_settings.clear_retries();
+                Ok(idle())
+            }
+            ProbeResponse::ExtraPoll(seconds) => {
+                debug!("server asked for an extra poll in {} seconds", seconds);
+                shared_state.runtime_settings.update.extra_poll_interval =
+                    Some(chrono::Duration::seconds(seconds));
+                Ok(idle())
+            }
+            ProbeResponse::Update(update_package, signature) => {
+                let package_uid = update_package.package_uid();
+                if already_applied(
+                    self.force,
+                    shared_state.runtime_settings.update.applied_package_uid.as_deref(),
+                    &package_uid,
+                ) {
+                    info!("ignoring already-applied package: {}", package_uid);
+                    return Ok(idle());
+                }
+
+                Ok((
+                    State::Validation(Validation { update_package, signature }),
+                    machine::StepTransition::Immediate,
+                ))
+            }
+        }
+    }
+}
+
+/// Whether `package_uid` is the update we last successfully installed
+/// and so can be skipped, short-circuiting straight back to
+/// `EntryPoint` without entering `PrepareDownload` or touching the
+/// network for objects, unless `force` asks to bypass the cache.
+fn already_applied(force: bool, applied_package_uid: Option<&str>, package_uid: &str) -> bool {
+    !force && applied_package_uid == Some(package_uid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skips_the_package_we_already_applied() {
+        assert!(already_applied(false, Some("abc123"), "abc123"));
+    }
+
+    #[test]
+    fn does_not_skip_a_different_package() {
+        assert!(!already_applied(false, Some("abc123"), "def456"));
+    }
+
+    #[test]
+    fn force_always_bypasses_the_cache() {
+        assert!(!already_applied(true, Some("abc123"), "abc123"));
+    }
+
+    #[test]
+    fn no_applied_package_is_never_skipped() {
+        assert!(!already_applied(false, None, "abc123"));
+    }
+}
@@ -0,0 +1,126 @@
+// Copyright (C) 2020 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Collects live device attributes (running kernel, free storage,
+//! hardware revision, boot count, ...) by running the executable (or
+//! directory of scripts) named by `Firmware.system_info`, so the
+//! server can take them into account when deciding whether an update
+//! applies. Results are cached for `Firmware.system_info_timeout` to
+//! avoid re-running the scripts on every probe.
+
+use chrono::{DateTime, Duration, Utc};
+use slog_scope::debug;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+pub(crate) type SystemInfo = HashMap<String, String>;
+
+struct Cached {
+    info: SystemInfo,
+    collected_at: DateTime<Utc>,
+}
+
+pub(crate) struct Collector {
+    path: Option<PathBuf>,
+    timeout: Duration,
+    cache: Mutex<Option<Cached>>,
+}
+
+impl Collector {
+    pub(crate) fn new(path: Option<PathBuf>, timeout: Duration) -> Self {
+        Self { path, timeout, cache: Mutex::new(None) }
+    }
+
+    /// Returns the cached system info, refreshing it by re-running
+    /// `path` if it is stale or hasn't been collected yet. Returns an
+    /// empty map when no `path` is configured.
+    pub(crate) fn collect(&self) -> SystemInfo {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return SystemInfo::default(),
+        };
+
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(cached) = cache.as_ref() {
+            if Utc::now() - cached.collected_at < self.timeout {
+                return cached.info.clone();
+            }
+        }
+
+        let info = run_scripts(path);
+        *cache = Some(Cached { info: info.clone(), collected_at: Utc::now() });
+        info
+    }
+}
+
+fn run_scripts(path: &Path) -> SystemInfo {
+    let mut info = SystemInfo::default();
+
+    let scripts: Vec<PathBuf> = if path.is_dir() {
+        let mut entries: Vec<PathBuf> = std::fs::read_dir(path)
+            .map(|entries| entries.filter_map(|e| e.ok()).map(|e| e.path()).collect())
+            .unwrap_or_default();
+        entries.sort();
+        entries
+    } else {
+        vec![path.to_owned()]
+    };
+
+    for script in scripts {
+        match easy_process::run(&script.to_string_lossy()) {
+            Ok(output) => match serde_json::from_str::<SystemInfo>(&output.stdout) {
+                Ok(values) => info.extend(values),
+                Err(e) => debug!("ignoring non key/value JSON output from {:?}: {}", script, e),
+            },
+            Err(e) => debug!("system-info script {:?} failed, skipping it: {}", script, e),
+        }
+    }
+
+    info
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn fake_script(body: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "#!/bin/sh\n{}", body).unwrap();
+        let mut perms = file.as_file().metadata().unwrap().permissions();
+        std::os::unix::fs::PermissionsExt::set_mode(&mut perms, 0o755);
+        file.as_file().set_permissions(perms).unwrap();
+        file
+    }
+
+    #[test]
+    fn collects_key_value_json_from_the_script() {
+        let script = fake_script(r#"echo '{"boot-count": "3"}'"#);
+        let collector = Collector::new(Some(script.path().to_owned()), Duration::minutes(5));
+
+        assert_eq!(collector.collect().get("boot-count"), Some(&"3".to_string()));
+    }
+
+    #[test]
+    fn caches_results_until_the_timeout_expires() {
+        let script = fake_script(r#"echo '{"value": "1"}'"#);
+        let collector = Collector::new(Some(script.path().to_owned()), Duration::minutes(5));
+
+        assert_eq!(collector.collect().get("value"), Some(&"1".to_string()));
+
+        // Overwrite the script; the cached value should still be
+        // returned since the timeout hasn't elapsed.
+        std::fs::write(script.path(), "#!/bin/sh\necho '{\"value\": \"2\"}'").unwrap();
+        assert_eq!(collector.collect().get("value"), Some(&"1".to_string()));
+    }
+
+    #[test]
+    fn no_path_configured_returns_an_empty_map() {
+        let collector = Collector::new(None, Duration::minutes(5));
+        assert!(collector.collect().is_empty());
+    }
+}